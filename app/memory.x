@@ -0,0 +1,17 @@
+/* M2 Bootloader RUST - application memory layout (STM32F411xE).
+   Must match docs/memory_map.md. */
+MEMORY
+{
+  /* Primary slot after the 0x200-byte image header, less the 0x40-byte
+     image state trailer at the end of the 128K swapped area. */
+  FLASH : ORIGIN = 0x08020200, LENGTH = 128K - 0x200 - 0x40
+  /* Everything but the shared regions at the top. */
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K - 64
+  /* Written by the bootloader before the jump (boot-core/src/bootinfo.rs). */
+  BOOT_INFO : ORIGIN = 0x2001FFC0, LENGTH = 48
+  /* Read by the bootloader after a software reset (boot-core/src/mailbox.rs). */
+  BOOT_REQUEST : ORIGIN = 0x2001FFF0, LENGTH = 16
+}
+
+__boot_info = ORIGIN(BOOT_INFO);
+__boot_request = ORIGIN(BOOT_REQUEST);
@@ -0,0 +1,111 @@
+//! M2 Bootloader RUST App Boot Client Module
+//! -----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+
+//! Cooperation with the bootloader.
+//!
+//! - `boot_info` reads what the bootloader left in the `BOOT_INFO` RAM
+//!   region before the jump (see memory.x).
+//! - `reboot_into` leaves a request in the `BOOT_REQUEST` mailbox and resets.
+//! - `with_client` lends a `boot_core::client::BootClient` over the internal
+//!   flash to confirm the image, query the slots or stream an update into the
+//!   secondary slot.
+
+use boot_core::bootinfo::{BootInfo, BootInfoError, BOOT_INFO_LEN};
+use boot_core::client::BootClient;
+use boot_core::mailbox::{BootRequest, MAILBOX_LEN};
+use boot_core::rollback::FlashCounter;
+use boot_core::slot::{Slot, SlotLayout};
+use boot_core::stm32f4::{InternalFlash, ProgramSize, VolatileMmio, STM32F411_SECTORS};
+use boot_core::watchdog::{FedFlash, Iwdg};
+use cortex_m::peripheral::SCB;
+
+/// Image slots. Must match `BOOT_SLOTS` in bootloader/src/main.rs, built
+/// with the same install strategy feature.
+#[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+pub const BOOT_SLOTS: SlotLayout = SlotLayout::new(
+    Slot::new(0x0002_0000, 0x0002_0000),
+    Slot::new(0x0004_0000, 0x0002_0000),
+    Slot::new(0x0006_0000, 0x0002_0000),
+    Slot::new(0x0001_0000, 0x0001_0000),
+);
+
+#[cfg(any(feature = "swap-move", feature = "overwrite-only"))]
+pub const BOOT_SLOTS: SlotLayout = SlotLayout::new(
+    Slot::new(0x0002_0000, 0x0004_0000),
+    Slot::new(0x0006_0000, 0x0002_0000),
+    Slot::new(0, 0),
+    Slot::new(0x0001_0000, 0x0001_0000),
+);
+
+/// Anti-rollback counter. Must match `SECURITY_COUNTER` in the bootloader.
+pub const SECURITY_COUNTER: FlashCounter = FlashCounter::new(Slot::new(0x0000_C000, 0x4000));
+
+/// Digest algorithm the bootloader checks images with.
+pub type BootDigest = boot_core::digest::Sha256;
+
+static mut FLASH: InternalFlash = InternalFlash::new(
+    VolatileMmio,
+    0x0800_0000,
+    &STM32F411_SECTORS,
+    256,
+    // x32 parallelism assumes VDD between 2.7 V and 3.6 V.
+    ProgramSize::X32,
+);
+
+/// Handle on the watchdog the bootloader left running, fed during flash work.
+static mut WATCHDOG: Iwdg = Iwdg::new(VolatileMmio);
+
+extern "C" {
+    // Start of the BOOT_INFO and BOOT_REQUEST regions, from memory.x.
+    static __boot_info: [u8; BOOT_INFO_LEN];
+    static mut __boot_request: [u8; MAILBOX_LEN];
+}
+
+/// Read the boot information record.
+///
+/// Fails when the bootloader left none, for example when the application
+/// was loaded and started by a debugger, or when the record is corrupted.
+pub fn boot_info() -> Result<BootInfo, BootInfoError> {
+    let mut bytes = [0u8; BOOT_INFO_LEN];
+    // SAFETY: the region is reserved in memory.x and only the bootloader
+    // writes it, before the application starts.
+    let src = unsafe { core::ptr::addr_of!(__boot_info) as *const u8 };
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(src.add(i)) };
+    }
+    BootInfo::parse(&bytes)
+}
+
+/// Reset into the bootloader with `request`: `BootRequest::Update` to stay
+/// in update mode (for now without an update link; the bootloader resets
+/// into a normal boot after a timeout), `BootRequest::BootSecondary` to run
+/// the image in the secondary slot on trial.
+pub fn reboot_into(request: BootRequest) -> ! {
+    // SAFETY: the region is reserved in memory.x and only read by the
+    // bootloader, after the reset below.
+    let mailbox = unsafe { core::ptr::addr_of_mut!(__boot_request) as *mut u8 };
+    for (i, byte) in request.encode().into_iter().enumerate() {
+        unsafe { core::ptr::write_volatile(mailbox.add(i), byte) };
+    }
+    // Completes the writes before the reset request.
+    SCB::sys_reset()
+}
+
+/// Run `f` with a boot client over the internal flash. Erases and programs
+/// feed the watchdog as they go, so a long sector erase does not reset the
+/// MCU.
+///
+/// Not reentrant: call from thread mode only, never from an interrupt
+/// handler.
+pub fn with_client<R>(f: impl FnOnce(&mut BootClient<'_>) -> R) -> R {
+    let (flash, watchdog) = unsafe { (&mut *core::ptr::addr_of_mut!(FLASH), &mut *core::ptr::addr_of_mut!(WATCHDOG)) };
+    let mut flash = FedFlash::new(flash, watchdog);
+    let mut client = BootClient::new(&mut flash, BOOT_SLOTS);
+    f(&mut client)
+}
@@ -12,8 +12,13 @@
 
 use cortex_m::asm;
 use cortex_m_rt::entry;
+mod boot_client;
 mod peripherals;
 
+/// Main loop iterations an image on trial has to run, with the watchdog
+/// fed, before it confirms itself.
+const CONFIRM_AFTER_BLINKS: u32 = 10;
+
 /// Entry point for the bootloader
 #[entry]
 fn main() -> ! {
@@ -23,13 +28,38 @@ fn main() -> ! {
     // Optional: indicate bootloader start
     peripherals::led_on();
 
+    // Started on trial after an update, the image confirms itself only once
+    // it has shown it works; unconfirmed, it is reverted after the
+    // bootloader's boot attempts. The checks below stand in for a real
+    // application's self-tests: the LED came up, and the main loop ran
+    // CONFIRM_AFTER_BLINKS times without hanging.
+    let mut on_trial = boot_client::with_client(|client| client.on_trial()) == Ok(true);
+    let mut blinks = 0;
+
     loop {
         // Toggle LED with a delay for visible blinking
         peripherals::toggle_led();
+        peripherals::feed_watchdog();
         delay();
+
+        if on_trial {
+            blinks += 1;
+            if blinks >= CONFIRM_AFTER_BLINKS {
+                if !self_test() {
+                    // Resetting uses up one of the trial's boot attempts.
+                    cortex_m::peripheral::SCB::sys_reset();
+                }
+                on_trial = boot_client::with_client(|client| client.confirm()).is_err();
+            }
+        }
     }
 }
 
+/// Placeholder self-test run before confirming an image on trial.
+fn self_test() -> bool {
+    peripherals::led_ready()
+}
+
 /// Simple busy-wait delay
 #[inline(always)]
 fn delay() {
@@ -41,6 +41,14 @@ pub fn init_led() {
     }
 }
 
+/// Whether the LED pin reads back as configured by `init_led`.
+pub fn led_ready() -> bool {
+    unsafe {
+        let moder = (GPIO_PORT_BASE + 0x00) as *const u32;
+        (ptr::read_volatile(moder) >> (LED_PIN * 2)) & 0b11 == 0b01
+    }
+}
+
 // -----------------------------------------------------------------------------
 // LED Control Functions
 // -----------------------------------------------------------------------------
@@ -77,3 +85,20 @@ pub fn toggle_led() {
         ptr::write_volatile(odr, ptr::read_volatile(odr) ^ (1 << LED_PIN));
     }
 }
+
+// -----------------------------------------------------------------------------
+// Watchdog
+// -----------------------------------------------------------------------------
+
+/// IWDG key register. The bootloader leaves the watchdog running.
+const IWDG_KR: u32 = 0x4000_3000;
+
+/// Feed the independent watchdog
+///
+/// Must be called more often than the bootloader's watchdog period, or the
+/// MCU resets and a trial boot counts as failed.
+pub fn feed_watchdog() {
+    unsafe {
+        ptr::write_volatile(IWDG_KR as *mut u32, 0xAAAA);
+    }
+}
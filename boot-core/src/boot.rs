@@ -0,0 +1,774 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Boot decision logic.
+//!
+//! Decides whether the image in a slot may be executed. The checks run in
+//! order of cost: header, payload digest, then (with `secure-boot`) the
+//! signature. With two slots the highest valid version wins; a newer image
+//! in the secondary slot is swapped into the primary slot first. Images
+//! whose security version is below the anti-rollback counter are refused
+//! like corrupted ones. Nothing here touches hardware, so the whole
+//! decision can be exercised on the host against `MockFlash`.
+
+use core::fmt;
+
+use crate::digest::{DigestType, ImageDigest};
+use crate::flash::{Flash, FlashError};
+use crate::image::{ImageError, ImageHeader};
+#[cfg(feature = "overwrite-only")]
+use crate::overwrite;
+use crate::rollback::SecurityCounter;
+use crate::slot::SlotLayout;
+#[cfg(not(feature = "overwrite-only"))]
+use crate::swap;
+use crate::trailer::{self, ImageState};
+use crate::updater::{UpdateError, UpdateMetadata};
+
+/// Reasons an image is refused at boot.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootError {
+    Flash(FlashError),
+    /// Missing, erased or corrupted image header.
+    InvalidHeader(ImageError),
+    /// Image digest algorithm differs from the one this build checks with.
+    UnsupportedDigest(DigestType),
+    DigestMismatch,
+    SignatureInvalid,
+    /// The image on trial used up its boot attempts and there is no other
+    /// image to fall back to.
+    AttemptsExhausted,
+    /// Security version below the anti-rollback counter.
+    Rollback(u16),
+}
+
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootError::Flash(e) => write!(f, "boot: {}", e),
+            BootError::InvalidHeader(e) => write!(f, "boot: invalid header ({})", e),
+            BootError::UnsupportedDigest(t) => write!(f, "boot: unsupported digest {}", t),
+            BootError::DigestMismatch => write!(f, "boot: image digest mismatch"),
+            BootError::SignatureInvalid => write!(f, "boot: image signature invalid"),
+            BootError::AttemptsExhausted => write!(f, "boot: boot attempts exhausted"),
+            BootError::Rollback(v) => write!(f, "boot: security version {} revoked", v),
+        }
+    }
+}
+
+impl From<FlashError> for BootError {
+    fn from(e: FlashError) -> Self {
+        BootError::Flash(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, BootError>;
+
+/// Validate the image stored in the slot at `slot_addr`, checking its
+/// payload with digest algorithm `D`.
+///
+/// Returns the image header when the image may be booted.
+pub fn validate_slot<D: ImageDigest>(flash: &dyn Flash, slot_addr: usize, slot_size: usize) -> Result<ImageHeader> {
+    // Parse and validate the header before trusting anything in the slot.
+    // An erased or half-written slot has no valid header.
+    let header = ImageHeader::read_from(flash, slot_addr, slot_size).map_err(|e| match e {
+        ImageError::Flash(e) => BootError::Flash(e),
+        e => BootError::InvalidHeader(e),
+    })?;
+
+    UpdateMetadata::from_header(&header, slot_addr)
+        .verify_payload::<D>(flash)
+        .map_err(|e| match e {
+            UpdateError::Flash(e) => BootError::Flash(e),
+            UpdateError::UnsupportedDigest(t) => BootError::UnsupportedDigest(t),
+            _ => BootError::DigestMismatch,
+        })?;
+
+    #[cfg(feature = "secure-boot")]
+    crate::signature::verify_image(flash, slot_addr, &header).map_err(|_| BootError::SignatureInvalid)?;
+
+    Ok(header)
+}
+
+/// [`validate_slot`], also refusing an image whose security version is below
+/// `floor`, the current anti-rollback counter.
+fn validate_current<D: ImageDigest>(
+    flash: &dyn Flash,
+    slot_addr: usize,
+    slot_size: usize,
+    floor: u32,
+) -> Result<ImageHeader> {
+    let header = validate_slot::<D>(flash, slot_addr, slot_size)?;
+    if u32::from(header.security_version) < floor {
+        return Err(BootError::Rollback(header.security_version));
+    }
+    Ok(header)
+}
+
+/// Raise `counter` to the security version of a confirmed image.
+///
+/// A counter with no room left is not fatal. The image was already checked
+/// against the current value, so it boots and the counter stays where it
+/// is: images below that value are still refused, only later security bumps
+/// no longer take effect.
+fn raise_counter(flash: &mut dyn Flash, counter: &dyn SecurityCounter, header: &ImageHeader) -> Result<()> {
+    match counter.advance(flash, u32::from(header.security_version)) {
+        Err(FlashError::OutOfBounds) => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Pick the image to boot from a primary/secondary layout.
+///
+/// A swap interrupted by a reset is finished first. An image on trial is
+/// booted again until it has used up `max_attempts` boots (capped at
+/// `trailer::MAX_BOOT_ATTEMPTS`) without the application confirming it, or
+/// at once when `watchdog_reset` says it hung the last time; it is then
+/// marked `Reverted` and swapped back out, bringing the previous image
+/// back into the primary slot. Otherwise the highest valid version wins: when
+/// that is the secondary image it is marked `Pending`, the two slots are
+/// swapped in full (trailers included) and the new primary image is checked
+/// again and booted on trial. A secondary image that is invalid, not newer or
+/// reverted before is ignored.
+///
+/// Images below the security `counter` count as invalid. The counter is
+/// raised to the security version of the primary image only when that image
+/// is booted outside a trial, that is once it was confirmed, so a trial that
+/// fails can still go back to the image before it. A counter that has run
+/// out of room stays at its last value and does not stop the boot.
+///
+/// Returns the header of the image now in the primary slot, or
+/// `BootError::AttemptsExhausted` when a failed trial has no image to fall
+/// back to.
+#[cfg(not(feature = "overwrite-only"))]
+pub fn select_image<D: ImageDigest>(
+    flash: &mut dyn Flash,
+    layout: &SlotLayout,
+    counter: &dyn SecurityCounter,
+    max_attempts: usize,
+    watchdog_reset: bool,
+) -> Result<ImageHeader> {
+    swap::resume(flash, layout)?;
+
+    let capacity = layout.image_capacity();
+    let floor = counter.read(flash)?;
+    let primary = validate_current::<D>(flash, layout.primary.addr, capacity, floor);
+    let secondary = validate_current::<D>(flash, layout.secondary.addr, capacity, floor);
+
+    let state = trailer::read_state(flash, layout, layout.primary)?;
+    let on_trial = matches!(state, Some(ImageState::Pending | ImageState::Testing));
+    // `Reverted` here means a revert was reset before its swap started.
+    let failed = state == Some(ImageState::Reverted)
+        || (on_trial && (primary.is_err() || attempts_exhausted(flash, layout, max_attempts)?))
+        || (state == Some(ImageState::Testing) && watchdog_reset);
+    if failed {
+        if secondary.is_err() {
+            return Err(BootError::AttemptsExhausted);
+        }
+        if state != Some(ImageState::Reverted) {
+            trailer::write_state(flash, layout, layout.primary, ImageState::Reverted)?;
+        }
+        swap::swap_slots(flash, layout, layout.secondary.size)?;
+        return validate_current::<D>(flash, layout.primary.addr, capacity, floor);
+    }
+    if on_trial {
+        trailer::write_state(flash, layout, layout.primary, ImageState::Testing)?;
+        return primary;
+    }
+
+    let install = match (&primary, &secondary) {
+        (Ok(p), Ok(s)) => s.version > p.version,
+        // Whatever is in the primary slot is kept, it may be of use later.
+        (Err(_), Ok(_)) => true,
+        (_, Err(_)) => false,
+    };
+    let staged = trailer::read_state(flash, layout, layout.secondary)?;
+    if !install || staged == Some(ImageState::Reverted) {
+        // Not on trial: confirmed, or programmed in the factory.
+        if let Ok(p) = &primary {
+            raise_counter(flash, counter, p)?;
+        }
+        return primary;
+    }
+
+    if staged != Some(ImageState::Pending) {
+        trailer::write_state(flash, layout, layout.secondary, ImageState::Pending)?;
+    }
+    swap::swap_slots(flash, layout, layout.secondary.size)?;
+    let header = validate_current::<D>(flash, layout.primary.addr, capacity, floor)?;
+    trailer::write_state(flash, layout, layout.primary, ImageState::Testing)?;
+    Ok(header)
+}
+
+/// Pick the image to boot from a primary/secondary layout, installing by
+/// overwrite (`overwrite-only`).
+///
+/// An install cut short by a reset is started over. Otherwise a valid
+/// secondary image is installed when it is newer than the primary image or
+/// the primary slot holds no valid image. The primary image is checked again
+/// after the copy and the secondary slot erased only once it passed; the
+/// previous image is gone. A new image is booted on trial like with a swap,
+/// but once it used up `max_attempts` boots unconfirmed, or hung and was
+/// reset by the watchdog, there is nothing to fall back to. The security
+/// `counter` is applied as with a swap.
+///
+/// Returns the header of the image now in the primary slot, or
+/// `BootError::AttemptsExhausted` for a failed trial.
+#[cfg(feature = "overwrite-only")]
+pub fn select_image<D: ImageDigest>(
+    flash: &mut dyn Flash,
+    layout: &SlotLayout,
+    counter: &dyn SecurityCounter,
+    max_attempts: usize,
+    watchdog_reset: bool,
+) -> Result<ImageHeader> {
+    let capacity = layout.image_capacity();
+    let floor = counter.read(flash)?;
+    let secondary = validate_current::<D>(flash, layout.secondary.addr, capacity, floor);
+
+    let header = if overwrite::in_progress(flash, layout)? {
+        // The primary slot holds part of the image at best. The secondary
+        // slot is untouched until an install finishes, so start over.
+        let staged = secondary?;
+        install::<D>(flash, layout, image_len(&staged))?
+    } else {
+        let primary = validate_current::<D>(flash, layout.primary.addr, capacity, floor);
+        match (primary, secondary) {
+            (Ok(p), Ok(s)) if s.version > p.version => install::<D>(flash, layout, image_len(&s))?,
+            (Ok(p), _) => p,
+            (Err(_), Ok(s)) => install::<D>(flash, layout, image_len(&s))?,
+            (Err(e), Err(_)) => return Err(e),
+        }
+    };
+
+    let state = trailer::read_state(flash, layout, layout.primary)?;
+    if matches!(state, Some(ImageState::Pending | ImageState::Testing)) {
+        let hung = state == Some(ImageState::Testing) && watchdog_reset;
+        if hung || attempts_exhausted(flash, layout, max_attempts)? {
+            return Err(BootError::AttemptsExhausted);
+        }
+        trailer::write_state(flash, layout, layout.primary, ImageState::Testing)?;
+    } else {
+        raise_counter(flash, counter, &header)?;
+    }
+    Ok(header)
+}
+
+#[cfg(feature = "overwrite-only")]
+fn install<D: ImageDigest>(flash: &mut dyn Flash, layout: &SlotLayout, len: usize) -> Result<ImageHeader> {
+    overwrite::install(flash, layout, len)?;
+    // On failure the marker stays set and the next boot copies again.
+    let header = validate_slot::<D>(flash, layout.primary.addr, layout.image_capacity())?;
+    trailer::write_state(flash, layout, layout.primary, ImageState::Pending)?;
+    overwrite::finish(flash, layout)?;
+    Ok(header)
+}
+
+/// Install the secondary image on trial whatever its version, as asked for by
+/// the application (see `mailbox.rs`). The next `select_image` boots it.
+///
+/// The image must still pass validation and the security `counter`. The
+/// image it replaces moves to the secondary slot and comes back if the trial
+/// fails.
+#[cfg(not(feature = "overwrite-only"))]
+pub fn activate_secondary<D: ImageDigest>(
+    flash: &mut dyn Flash,
+    layout: &SlotLayout,
+    counter: &dyn SecurityCounter,
+) -> Result<()> {
+    swap::resume(flash, layout)?;
+    let floor = counter.read(flash)?;
+    validate_current::<D>(flash, layout.secondary.addr, layout.image_capacity(), floor)?;
+    if trailer::read_state(flash, layout, layout.secondary)? != Some(ImageState::Pending) {
+        trailer::write_state(flash, layout, layout.secondary, ImageState::Pending)?;
+    }
+    swap::swap_slots(flash, layout, layout.secondary.size)?;
+    Ok(())
+}
+
+/// Install the secondary image on trial whatever its version, as asked for by
+/// the application (see `mailbox.rs`), by overwrite. The next `select_image`
+/// boots it.
+///
+/// The image must still pass validation and the security `counter`. The
+/// image it replaces is gone.
+#[cfg(feature = "overwrite-only")]
+pub fn activate_secondary<D: ImageDigest>(
+    flash: &mut dyn Flash,
+    layout: &SlotLayout,
+    counter: &dyn SecurityCounter,
+) -> Result<()> {
+    let floor = counter.read(flash)?;
+    let staged = validate_current::<D>(flash, layout.secondary.addr, layout.image_capacity(), floor)?;
+    install::<D>(flash, layout, image_len(&staged))?;
+    Ok(())
+}
+
+/// Whether the image on trial in the primary slot has been booted
+/// `max_attempts` times already.
+fn attempts_exhausted(flash: &dyn Flash, layout: &SlotLayout, max_attempts: usize) -> Result<bool> {
+    let max_attempts = max_attempts.clamp(1, trailer::MAX_BOOT_ATTEMPTS);
+    Ok(trailer::boot_attempts(flash, layout, layout.primary)? >= max_attempts)
+}
+
+#[cfg(feature = "overwrite-only")]
+fn image_len(header: &ImageHeader) -> usize {
+    header.header_size as usize + header.image_size as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::{Crc32, ExpectedDigest, Sha256};
+    use crate::flash::MockFlash;
+    use crate::image::{ImageVersion, IMAGE_HEADER_ENCODED_LEN, IMAGE_HEADER_SIZE};
+
+    const SLOT: usize = 0x800;
+    const SLOT_SIZE: usize = 0x1000;
+
+    fn write_image_at(flash: &mut MockFlash, slot: usize, version: ImageVersion, payload: &[u8]) -> ImageHeader {
+        write_secure_image_at(flash, slot, version, 0, payload)
+    }
+
+    /// Write an image into `slot`, signed with the development key in
+    /// `secure-boot` builds.
+    fn write_secure_image_at(
+        flash: &mut MockFlash,
+        slot: usize,
+        version: ImageVersion,
+        security_version: u16,
+        payload: &[u8],
+    ) -> ImageHeader {
+        let (header, image) = unsigned_image(version, security_version, payload);
+        flash.write_region(slot, &image).unwrap();
+        #[cfg(feature = "secure-boot")]
+        {
+            use crate::image::IMAGE_SIGNATURE_OFFSET;
+            use crate::signature::test_keys::{sign_hash, DEV_SEED};
+
+            let hash = crate::signature::image_hash(flash, slot, &header).unwrap();
+            flash.program_page(slot + IMAGE_SIGNATURE_OFFSET, &sign_hash(&DEV_SEED, &hash)).unwrap();
+        }
+        header
+    }
+
+    fn unsigned_image(version: ImageVersion, security_version: u16, payload: &[u8]) -> (ImageHeader, Vec<u8>) {
+        let mut d = Sha256::new();
+        d.update(payload);
+        let mut header = ImageHeader::new(
+            0x0800_4200,
+            payload.len() as u32,
+            version,
+            ExpectedDigest::Sha256(d.finalize()),
+        );
+        header.security_version = security_version;
+        let mut image = vec![0xFFu8; IMAGE_HEADER_SIZE + payload.len()];
+        image[..IMAGE_HEADER_ENCODED_LEN].copy_from_slice(&header.to_bytes());
+        image[IMAGE_HEADER_SIZE..].copy_from_slice(payload);
+        (header, image)
+    }
+
+    fn write_image(flash: &mut MockFlash, payload: &[u8]) -> ImageHeader {
+        write_image_at(flash, SLOT, ImageVersion::new(1, 0, 0, 0), payload)
+    }
+
+    #[test]
+    fn erased_slot_is_refused() {
+        let f = MockFlash::new(0x2000, 0x400, 0x100);
+        assert!(matches!(validate_slot::<Sha256>(&f, SLOT, SLOT_SIZE), Err(BootError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn intact_image_is_accepted() {
+        let mut f = MockFlash::new(0x2000, 0x400, 0x100);
+        let header = write_image(&mut f, &[0x3Cu8; 700]);
+        assert_eq!(validate_slot::<Sha256>(&f, SLOT, SLOT_SIZE), Ok(header));
+    }
+
+    #[test]
+    fn corrupted_payload_is_refused() {
+        let mut f = MockFlash::new(0x2000, 0x400, 0x100);
+        write_image(&mut f, &[0x3Cu8; 700]);
+        f.program_page(SLOT + IMAGE_HEADER_SIZE + 5, &[0x00]).unwrap();
+        assert_eq!(validate_slot::<Sha256>(&f, SLOT, SLOT_SIZE), Err(BootError::DigestMismatch));
+    }
+
+    #[test]
+    fn flipped_bits_are_refused() {
+        use crate::flash::FlipOn;
+
+        let mut f = MockFlash::new(0x2000, 0x400, 0x100);
+        write_image(&mut f, &[0x3Cu8; 700]);
+        f.flip_bit(SLOT + IMAGE_HEADER_SIZE + 699, 1, FlipOn::Read);
+        assert_eq!(validate_slot::<Sha256>(&f, SLOT, SLOT_SIZE), Err(BootError::DigestMismatch));
+
+        f.clear_bit_flips();
+        f.flip_bit(SLOT + 2, 0, FlipOn::Read);
+        assert!(matches!(validate_slot::<Sha256>(&f, SLOT, SLOT_SIZE), Err(BootError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn digest_algorithm_must_match_build() {
+        let mut f = MockFlash::new(0x2000, 0x400, 0x100);
+        write_image(&mut f, &[0x3Cu8; 700]);
+        assert_eq!(
+            validate_slot::<Crc32>(&f, SLOT, SLOT_SIZE),
+            Err(BootError::UnsupportedDigest(DigestType::Sha256))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "secure-boot")]
+    fn unsigned_image_is_refused() {
+        let mut f = MockFlash::new(0x2000, 0x400, 0x100);
+        let (_, image) = unsigned_image(ImageVersion::new(1, 0, 0, 0), 0, &[0x3Cu8; 700]);
+        f.write_region(SLOT, &image).unwrap();
+        assert_eq!(validate_slot::<Sha256>(&f, SLOT, SLOT_SIZE), Err(BootError::SignatureInvalid));
+    }
+
+    mod dual_slot {
+        use super::*;
+        use crate::rollback::FlashCounter;
+        use crate::slot::Slot;
+
+        // Free in all three layouts below.
+        const COUNTER: FlashCounter = FlashCounter::new(Slot::new(0x0400, 0x400));
+
+        #[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+        const LAYOUT: SlotLayout = SlotLayout::new(
+            Slot::new(0x1000, 0x1000),
+            Slot::new(0x2000, 0x1000),
+            Slot::new(0x3000, 0x400),
+            Slot::new(0x0000, 0x400),
+        );
+
+        #[cfg(feature = "swap-move")]
+        const LAYOUT: SlotLayout = SlotLayout::new(
+            Slot::new(0x1000, 0x1400),
+            Slot::new(0x2400, 0x1000),
+            Slot::new(0, 0),
+            Slot::new(0x0000, 0x400),
+        );
+
+        #[cfg(feature = "overwrite-only")]
+        const LAYOUT: SlotLayout = SlotLayout::new(
+            Slot::new(0x1000, 0x1000),
+            Slot::new(0x2000, 0x1000),
+            Slot::new(0, 0),
+            Slot::new(0x0000, 0x400),
+        );
+
+        #[test]
+        #[cfg(not(feature = "overwrite-only"))]
+        fn newer_secondary_is_swapped_in() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let old = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+            assert_eq!(validate_slot::<Sha256>(&f, LAYOUT.primary.addr, LAYOUT.primary.size), Ok(staged));
+            assert_eq!(validate_slot::<Sha256>(&f, LAYOUT.secondary.addr, LAYOUT.secondary.size), Ok(old));
+            assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Testing)));
+
+            // Once confirmed there is nothing left to do on the next boot.
+            trailer::confirm(&mut f, &LAYOUT).unwrap();
+            let before = f.storage.clone();
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+            assert_eq!(f.storage, before);
+        }
+
+        #[test]
+        #[cfg(not(feature = "overwrite-only"))]
+        fn unconfirmed_trial_is_reverted() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let old = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+
+            // Reset without confirming: the previous image comes back and the
+            // failed one is never tried again.
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(old));
+            assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.secondary), Ok(Some(ImageState::Reverted)));
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(old));
+            assert_eq!(validate_slot::<Sha256>(&f, LAYOUT.secondary.addr, LAYOUT.secondary.size), Ok(staged));
+        }
+
+        #[test]
+        #[cfg(not(feature = "overwrite-only"))]
+        fn trial_gets_its_boot_attempts() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let old = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+
+            for attempt in 1..=3 {
+                assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false), Ok(staged));
+                assert_eq!(trailer::boot_attempts(&f, &LAYOUT, LAYOUT.primary), Ok(attempt));
+            }
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false), Ok(old));
+
+            // Confirming within the limit keeps the image for good.
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+            select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false).unwrap();
+            select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false).unwrap();
+            trailer::confirm(&mut f, &LAYOUT).unwrap();
+            for _ in 0..5 {
+                assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false), Ok(staged));
+            }
+        }
+
+        #[test]
+        #[cfg(not(feature = "overwrite-only"))]
+        fn watchdog_reset_fails_the_trial() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let old = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+
+            // A watchdog reset of the image that was running before the
+            // install is not held against the new one.
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, true), Ok(staged));
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, true), Ok(old));
+        }
+
+        #[test]
+        #[cfg(not(feature = "overwrite-only"))]
+        fn reset_before_trial_starts_it() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+
+            // Swapped in, but reset before the image was marked as on trial.
+            trailer::write_state(&mut f, &LAYOUT, LAYOUT.secondary, ImageState::Pending).unwrap();
+            swap::swap_slots(&mut f, &LAYOUT, LAYOUT.secondary.size).unwrap();
+
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+            assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Testing)));
+        }
+
+        /// Flash with version 1.0 running and 1.1 staged, and the power-on
+        /// operation count reset.
+        fn staged_update() -> (MockFlash, ImageHeader, ImageHeader) {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let old = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+            f.reboot();
+            (f, old, staged)
+        }
+
+        #[test]
+        fn power_loss_while_starting_a_trial() {
+            let (mut f, _, staged) = staged_update();
+            select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false).unwrap();
+            let steps = f.ops();
+
+            // Cut while marking the image `Pending`, installing it and
+            // marking it `Testing`.
+            for cut in 0..steps {
+                let (mut f, _, _) = staged_update();
+                f.seed_noise(cut as u32 + 1);
+                f.cut_power_after(cut);
+                assert_eq!(
+                    select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false),
+                    Err(BootError::Flash(MockFlash::POWER_LOSS))
+                );
+                f.reboot();
+
+                // The next boot finishes the install and starts the trial,
+                // and the lost boot does not count against it.
+                assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged), "cut {cut}");
+                assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Testing)));
+                assert_eq!(trailer::boot_attempts(&f, &LAYOUT, LAYOUT.primary), Ok(1));
+            }
+        }
+
+        #[test]
+        #[cfg(not(feature = "overwrite-only"))]
+        fn power_loss_while_reverting_a_trial() {
+            let trial = || {
+                let (mut f, old, staged) = staged_update();
+                select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false).unwrap();
+                f.reboot();
+                (f, old, staged)
+            };
+            let (mut f, _, _) = trial();
+            select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false).unwrap();
+            let steps = f.ops();
+
+            // Cut while marking the failed image `Reverted` and swapping the
+            // previous one back.
+            for cut in 0..steps {
+                let (mut f, old, staged) = trial();
+                f.seed_noise(cut as u32 + 1);
+                f.cut_power_after(cut);
+                assert_eq!(
+                    select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false),
+                    Err(BootError::Flash(MockFlash::POWER_LOSS))
+                );
+                f.reboot();
+
+                // The revert is finished and the failed image stays out.
+                assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(old), "cut {cut}");
+                assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.secondary), Ok(Some(ImageState::Reverted)));
+                assert_eq!(validate_slot::<Sha256>(&f, LAYOUT.secondary.addr, LAYOUT.secondary.size), Ok(staged));
+                let before = f.storage.clone();
+                assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(old));
+                assert_eq!(f.storage, before);
+            }
+        }
+
+        #[test]
+        #[cfg(feature = "overwrite-only")]
+        fn newer_secondary_overwrites_primary() {
+            let mut f = MockFlash::new(0x3000, 0x400, 0x100);
+            write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 1, 0, 0), &[0x22u8; 900]);
+
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+            assert_eq!(validate_slot::<Sha256>(&f, LAYOUT.primary.addr, LAYOUT.primary.size), Ok(staged));
+            // The staging slot is erased so the image is installed only once.
+            assert!(f.storage[LAYOUT.secondary.addr..LAYOUT.secondary.end()].iter().all(|&b| b == 0xFF));
+            assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Testing)));
+            trailer::confirm(&mut f, &LAYOUT).unwrap();
+            let before = f.storage.clone();
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+            assert_eq!(f.storage, before);
+        }
+
+        #[test]
+        #[cfg(feature = "overwrite-only")]
+        fn interrupted_overwrite_starts_over() {
+            let mut f = MockFlash::new(0x3000, 0x400, 0x100);
+            write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), &[0x11u8; 600]);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(0, 9, 0, 0), &[0x22u8; 900]);
+
+            // Reset after the marker was written and part of the image copied.
+            overwrite::install(&mut f, &LAYOUT, image_len(&staged)).unwrap();
+            f.erase_sector(LAYOUT.primary.addr + 0x400).unwrap();
+
+            // The partial image is not booted even though the staged version
+            // is lower than the one the copy replaced.
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+            assert_eq!(overwrite::in_progress(&f, &LAYOUT), Ok(false));
+        }
+
+        #[test]
+        fn failed_trial_without_fallback_enters_recovery() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 0, 0, 0), &[0x22u8; 900]);
+
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 2, false), Ok(staged));
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 2, false), Ok(staged));
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 2, false), Err(BootError::AttemptsExhausted));
+
+            // A watchdog reset ends the trial right away.
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 0, 0, 0), &[0x22u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 2, false), Ok(staged));
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 2, true), Err(BootError::AttemptsExhausted));
+        }
+
+        #[test]
+        fn bad_or_older_secondary_keeps_primary() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let running = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(2, 0, 0, 0), &[0x11u8; 600]);
+
+            write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 9, 0, 0), &[0x22u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+
+            // A newer but corrupted download never replaces the running image.
+            write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(3, 0, 0, 0), &[0x22u8; 900]);
+            f.program_page(LAYOUT.secondary.addr + IMAGE_HEADER_SIZE + 1, &[0x00]).unwrap();
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+        }
+
+        #[test]
+        fn security_counter_follows_confirmed_image() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            write_secure_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), 0, &[0x11u8; 600]);
+            let staged =
+                write_secure_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(2, 0, 0, 0), 1, &[0x22u8; 900]);
+
+            // The counter stays put while the image is on trial...
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false), Ok(staged));
+            assert_eq!(COUNTER.read(&f), Ok(0));
+            // ...and moves once it was confirmed.
+            trailer::confirm(&mut f, &LAYOUT).unwrap();
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false), Ok(staged));
+            assert_eq!(COUNTER.read(&f), Ok(1));
+
+            // A newer version carrying a revoked security version is ignored.
+            write_secure_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(3, 0, 0, 0), 0, &[0x33u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 3, false), Ok(staged));
+        }
+
+        #[test]
+        fn full_counter_area_still_boots() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let mut last = 0;
+            while COUNTER.advance(&mut f, last + 1).is_ok() {
+                last += 1;
+            }
+            let running =
+                write_secure_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), 200, &[0x11u8; 600]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+            assert_eq!(COUNTER.read(&f), Ok(last));
+
+            // Images below the last value are still refused.
+            let revoked = last as u16 - 1;
+            write_secure_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(2, 0, 0, 0), revoked, &[0x22u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+        }
+
+        #[test]
+        fn revoked_primary_is_refused() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            COUNTER.advance(&mut f, 2).unwrap();
+            write_secure_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(1, 0, 0, 0), 1, &[0x11u8; 600]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Err(BootError::Rollback(1)));
+        }
+
+        #[test]
+        fn older_secondary_is_installed_on_request() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            let running = write_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(2, 0, 0, 0), &[0x11u8; 600]);
+            let older = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 0, 0, 0), &[0x22u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+
+            activate_secondary::<Sha256>(&mut f, &LAYOUT, &COUNTER).unwrap();
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(older));
+            assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Testing)));
+
+            // Unconfirmed, it is reverted like any other trial.
+            #[cfg(not(feature = "overwrite-only"))]
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(running));
+        }
+
+        #[test]
+        fn revoked_secondary_is_not_installed_on_request() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            COUNTER.advance(&mut f, 1).unwrap();
+            write_secure_image_at(&mut f, LAYOUT.primary.addr, ImageVersion::new(2, 0, 0, 0), 1, &[0x11u8; 600]);
+            write_secure_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 0, 0, 0), 0, &[0x22u8; 900]);
+            let before = f.storage.clone();
+            assert_eq!(activate_secondary::<Sha256>(&mut f, &LAYOUT, &COUNTER), Err(BootError::Rollback(0)));
+            assert_eq!(f.storage, before);
+        }
+
+        #[test]
+        fn empty_primary_takes_secondary() {
+            let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+            assert!(matches!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Err(BootError::InvalidHeader(_))));
+
+            let staged = write_image_at(&mut f, LAYOUT.secondary.addr, ImageVersion::new(1, 0, 0, 0), &[0x22u8; 900]);
+            assert_eq!(select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(staged));
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Boot information handed from the bootloader to the application.
+//!
+//! Just before the jump the bootloader writes a `BootInfo` record into a
+//! small RAM region both images' linker scripts leave out of `RAM` (see
+//! `memory.x` and docs/memory_map.md), so neither startup code zeroes it.
+//! The record says why and from where the application was started: boot
+//! reason, slot, image and bootloader versions, reset cause and trial boot
+//! count.
+//!
+//! The region is not initialised on power-on and keeps whatever the last
+//! run left there across resets, so the record carries a magic, a layout
+//! version and a CRC32; the application trusts it only when all three check
+//! out. Fields are little-endian at fixed offsets, like the image header:
+//!
+//! ```text
+//! 0x00 magic          0x08 reason        0x0C slot address
+//! 0x04 layout version 0x09 reset cause   0x10 image version
+//! 0x06 length         0x0A boot attempts 0x18 bootloader version
+//!                                        0x20 CRC32 of 0x00..0x20
+//! ```
+
+use core::fmt;
+
+use crate::flash::{self, Flash};
+use crate::image::{ImageHeader, ImageVersion};
+use crate::slot::SlotLayout;
+use crate::trailer::{self, ImageState};
+use crate::watchdog::ResetCause;
+
+/// "BINF", little-endian.
+pub const BOOT_INFO_MAGIC: u32 = 0x464E_4942;
+
+/// Layout version of the record. Bump when fields move.
+pub const BOOT_INFO_VERSION: u16 = 1;
+
+/// Encoded record length, CRC included.
+pub const BOOT_INFO_LEN: usize = 0x24;
+
+const CRC_OFFSET: usize = 0x20;
+
+/// Errors returned while reading a boot information record.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootInfoError {
+    /// No record: cold boot, or started without the bootloader.
+    BadMagic(u32),
+    UnsupportedVersion(u16),
+    CrcMismatch { expected: u32, found: u32 },
+    /// A field holds a value this layout version does not define.
+    InvalidValue { offset: usize, value: u8 },
+}
+
+impl fmt::Display for BootInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootInfoError::BadMagic(m) => write!(f, "boot info: bad magic {:#010x}", m),
+            BootInfoError::UnsupportedVersion(v) => write!(f, "boot info: unsupported layout version {}", v),
+            BootInfoError::CrcMismatch { expected, found } => {
+                write!(f, "boot info: CRC mismatch (expected {:#010x}, found {:#010x})", expected, found)
+            }
+            BootInfoError::InvalidValue { offset, value } => {
+                write!(f, "boot info: invalid value {:#04x} at offset {:#04x}", value, offset)
+            }
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, BootInfoError>;
+
+/// Why the bootloader started the image it did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootReason {
+    /// Confirmed or factory-programmed image, nothing changed.
+    Normal,
+    /// First boot of a newly installed image, on trial.
+    Update,
+    /// Another boot of an image still on trial.
+    Trial,
+    /// The last update failed its trial and the previous image is back.
+    /// Reported until a new image is staged.
+    Reverted,
+}
+
+impl BootReason {
+    const fn code(self) -> u8 {
+        match self {
+            BootReason::Normal => 0,
+            BootReason::Update => 1,
+            BootReason::Trial => 2,
+            BootReason::Reverted => 3,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        [BootReason::Normal, BootReason::Update, BootReason::Trial, BootReason::Reverted]
+            .into_iter()
+            .find(|r| r.code() == code)
+    }
+}
+
+impl fmt::Display for BootReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BootReason::Normal => write!(f, "normal"),
+            BootReason::Update => write!(f, "update"),
+            BootReason::Trial => write!(f, "trial"),
+            BootReason::Reverted => write!(f, "reverted"),
+        }
+    }
+}
+
+const RESET_CAUSES: [ResetCause; 6] = [
+    ResetCause::Unknown,
+    ResetCause::Watchdog,
+    ResetCause::LowPower,
+    ResetCause::Software,
+    ResetCause::PowerOn,
+    ResetCause::Pin,
+];
+
+/// Boot information record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootInfo {
+    pub reason: BootReason,
+    pub reset_cause: ResetCause,
+    /// Boots on trial since the image was installed, this one included while
+    /// it is still on trial. Zero for a factory-programmed image.
+    pub boot_attempts: u8,
+    /// Absolute address of the slot the image runs from.
+    pub slot_addr: u32,
+    pub image_version: ImageVersion,
+    pub bootloader_version: ImageVersion,
+}
+
+impl BootInfo {
+    /// Describe the boot `boot::select_image` just decided on, `header` being
+    /// the header it returned. `flash_base` is the address flash offset zero
+    /// is mapped at.
+    pub fn gather(
+        flash: &dyn Flash,
+        layout: &SlotLayout,
+        flash_base: u32,
+        header: &ImageHeader,
+        reset_cause: ResetCause,
+        bootloader_version: ImageVersion,
+    ) -> flash::Result<Self> {
+        let attempts = trailer::boot_attempts(flash, layout, layout.primary)?;
+        let reason = match trailer::read_state(flash, layout, layout.primary)? {
+            Some(ImageState::Testing) if attempts <= 1 => BootReason::Update,
+            Some(ImageState::Testing) => BootReason::Trial,
+            // A reverted image is swapped out along with its trailer.
+            _ if trailer::read_state(flash, layout, layout.secondary)? == Some(ImageState::Reverted) => {
+                BootReason::Reverted
+            }
+            _ => BootReason::Normal,
+        };
+        Ok(BootInfo {
+            reason,
+            reset_cause,
+            boot_attempts: attempts.min(u8::MAX as usize) as u8,
+            slot_addr: flash_base + layout.primary.addr as u32,
+            image_version: header.version,
+            bootloader_version,
+        })
+    }
+
+    /// Parse and check a record read from the boot information region.
+    pub fn parse(bytes: &[u8; BOOT_INFO_LEN]) -> Result<Self> {
+        let magic = le_u32(bytes, 0x00);
+        if magic != BOOT_INFO_MAGIC {
+            return Err(BootInfoError::BadMagic(magic));
+        }
+        let version = u16::from_le_bytes([bytes[0x04], bytes[0x05]]);
+        if version != BOOT_INFO_VERSION {
+            return Err(BootInfoError::UnsupportedVersion(version));
+        }
+        let stored_crc = le_u32(bytes, CRC_OFFSET);
+        let computed_crc = crc32(&bytes[..CRC_OFFSET]);
+        if stored_crc != computed_crc {
+            return Err(BootInfoError::CrcMismatch { expected: stored_crc, found: computed_crc });
+        }
+
+        let reason =
+            BootReason::from_code(bytes[0x08]).ok_or(BootInfoError::InvalidValue { offset: 0x08, value: bytes[0x08] })?;
+        let reset_cause = *RESET_CAUSES
+            .get(bytes[0x09] as usize)
+            .ok_or(BootInfoError::InvalidValue { offset: 0x09, value: bytes[0x09] })?;
+        Ok(BootInfo {
+            reason,
+            reset_cause,
+            boot_attempts: bytes[0x0A],
+            slot_addr: le_u32(bytes, 0x0C),
+            image_version: read_version(bytes, 0x10),
+            bootloader_version: read_version(bytes, 0x18),
+        })
+    }
+
+    /// Encode the record, computing its CRC.
+    pub fn to_bytes(&self) -> [u8; BOOT_INFO_LEN] {
+        let mut out = [0u8; BOOT_INFO_LEN];
+        out[0x00..0x04].copy_from_slice(&BOOT_INFO_MAGIC.to_le_bytes());
+        out[0x04..0x06].copy_from_slice(&BOOT_INFO_VERSION.to_le_bytes());
+        out[0x06..0x08].copy_from_slice(&(BOOT_INFO_LEN as u16).to_le_bytes());
+        out[0x08] = self.reason.code();
+        out[0x09] = RESET_CAUSES.iter().position(|&c| c == self.reset_cause).unwrap_or(0) as u8;
+        out[0x0A] = self.boot_attempts;
+        out[0x0C..0x10].copy_from_slice(&self.slot_addr.to_le_bytes());
+        write_version(&mut out, 0x10, self.image_version);
+        write_version(&mut out, 0x18, self.bootloader_version);
+        let crc = crc32(&out[..CRC_OFFSET]);
+        out[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+}
+
+fn read_version(bytes: &[u8], offset: usize) -> ImageVersion {
+    ImageVersion {
+        major: bytes[offset],
+        minor: bytes[offset + 1],
+        patch: u16::from_le_bytes([bytes[offset + 2], bytes[offset + 3]]),
+        build: le_u32(bytes, offset + 4),
+    }
+}
+
+fn write_version(out: &mut [u8], offset: usize, version: ImageVersion) {
+    out[offset] = version.major;
+    out[offset + 1] = version.minor;
+    out[offset + 2..offset + 4].copy_from_slice(&version.patch.to_le_bytes());
+    out[offset + 4..offset + 8].copy_from_slice(&version.build.to_le_bytes());
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = crc_any::CRCu32::crc32();
+    crc.digest(bytes);
+    crc.get_crc()
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::ExpectedDigest;
+    use crate::flash::MockFlash;
+    use crate::slot::Slot;
+
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x100, 0x100),
+        Slot::new(0x200, 0x100),
+        Slot::new(0x300, 0x100),
+        Slot::new(0x000, 0x100),
+    );
+
+    fn info() -> BootInfo {
+        BootInfo {
+            reason: BootReason::Trial,
+            reset_cause: ResetCause::Watchdog,
+            boot_attempts: 2,
+            slot_addr: 0x0802_0000,
+            image_version: ImageVersion::new(1, 4, 2, 77),
+            bootloader_version: ImageVersion::new(0, 1, 0, 0),
+        }
+    }
+
+    #[test]
+    fn record_round_trips() {
+        let bytes = info().to_bytes();
+        assert_eq!(&bytes[..4], b"BINF");
+        assert_eq!(BootInfo::parse(&bytes), Ok(info()));
+        for cause in RESET_CAUSES {
+            let record = BootInfo { reset_cause: cause, ..info() };
+            assert_eq!(BootInfo::parse(&record.to_bytes()), Ok(record));
+        }
+    }
+
+    #[test]
+    fn stale_or_garbled_records_are_refused() {
+        // Power-on leaves the region undefined.
+        assert_eq!(BootInfo::parse(&[0xA5; BOOT_INFO_LEN]), Err(BootInfoError::BadMagic(0xA5A5_A5A5)));
+
+        let mut bytes = info().to_bytes();
+        bytes[0x04] = 2;
+        assert_eq!(BootInfo::parse(&bytes), Err(BootInfoError::UnsupportedVersion(2)));
+
+        let mut bytes = info().to_bytes();
+        bytes[0x11] ^= 0x01;
+        assert!(matches!(BootInfo::parse(&bytes), Err(BootInfoError::CrcMismatch { .. })));
+
+        // Well-formed, but a reason this layout does not know.
+        let mut bytes = info().to_bytes();
+        bytes[0x08] = 9;
+        let crc = crc32(&bytes[..CRC_OFFSET]);
+        bytes[CRC_OFFSET..].copy_from_slice(&crc.to_le_bytes());
+        assert_eq!(BootInfo::parse(&bytes), Err(BootInfoError::InvalidValue { offset: 0x08, value: 9 }));
+    }
+
+    #[test]
+    fn reason_follows_the_trailers() {
+        let mut f = MockFlash::new(0x400, 0x100, 0x20);
+        let header = ImageHeader::new(0x0800_0300, 0x80, ImageVersion::new(2, 0, 0, 0), ExpectedDigest::Crc32(0));
+        let gather = |f: &MockFlash| {
+            BootInfo::gather(f, &LAYOUT, 0x0800_0000, &header, ResetCause::PowerOn, ImageVersion::default()).unwrap()
+        };
+
+        let factory = gather(&f);
+        assert_eq!((factory.reason, factory.boot_attempts), (BootReason::Normal, 0));
+        assert_eq!(factory.slot_addr, 0x0800_0100);
+        assert_eq!(factory.image_version, header.version);
+
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Pending).unwrap();
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Testing).unwrap();
+        assert_eq!(gather(&f).reason, BootReason::Update);
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Testing).unwrap();
+        assert_eq!((gather(&f).reason, gather(&f).boot_attempts), (BootReason::Trial, 2));
+        trailer::confirm(&mut f, &LAYOUT).unwrap();
+        assert_eq!(gather(&f).reason, BootReason::Normal);
+
+        // The failed image sits in the secondary slot after the revert swap.
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.secondary, ImageState::Reverted).unwrap();
+        assert_eq!(gather(&f).reason, BootReason::Reverted);
+    }
+}
@@ -0,0 +1,221 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Application side of the bootloader.
+//!
+//! `BootClient` is what a running application needs to cooperate with the
+//! bootloader through flash: confirm itself after a trial boot, see what the
+//! slots hold, and stream a new image into the secondary slot while it keeps
+//! running. Staging goes through `SlotLayout::begin_update` and
+//! `FirmwareUpdater`, the same code the bootloader uses, so an image the
+//! client accepts is one the bootloader will install on the next reset.
+//!
+//! Boot information and reboot requests travel through RAM instead; see
+//! `bootinfo.rs` and `mailbox.rs`.
+
+use crate::digest::ImageDigest;
+use crate::flash::{self, Flash};
+use crate::image::{ImageError, ImageHeader};
+use crate::rollback::SecurityCounter;
+use crate::slot::SlotLayout;
+use crate::trailer::{self, ImageState};
+use crate::updater::{FirmwareUpdater, UpdateError, UpdateResult};
+
+/// The two image slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotId {
+    /// Where the running image executes from.
+    Primary,
+    /// Where updates are staged.
+    Secondary,
+}
+
+/// What a slot holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotStatus {
+    /// Image header, when the slot has a valid one. The payload is not
+    /// checked here; the bootloader does that before installing or booting.
+    pub header: Option<ImageHeader>,
+    /// Trailer state, `None` when erased (e.g. factory-programmed image).
+    pub state: Option<ImageState>,
+}
+
+/// Application-side access to the bootloader's slots.
+pub struct BootClient<'a> {
+    flash: &'a mut dyn Flash,
+    layout: SlotLayout,
+}
+
+impl<'a> BootClient<'a> {
+    /// `layout` must be the one the bootloader was built with.
+    pub fn new(flash: &'a mut dyn Flash, layout: SlotLayout) -> Self {
+        BootClient { flash, layout }
+    }
+
+    /// Keep the running image. Called once the application is satisfied the
+    /// image works; until then every reset counts as a failed boot attempt.
+    pub fn confirm(&mut self) -> flash::Result<()> {
+        trailer::confirm(self.flash, &self.layout)
+    }
+
+    /// Whether the running image is on trial and still needs confirming.
+    pub fn on_trial(&self) -> flash::Result<bool> {
+        let state = trailer::read_state(self.flash, &self.layout, self.layout.primary)?;
+        Ok(matches!(state, Some(ImageState::Pending | ImageState::Testing)))
+    }
+
+    pub fn slot_status(&self, slot: SlotId) -> flash::Result<SlotStatus> {
+        let slot = match slot {
+            SlotId::Primary => self.layout.primary,
+            SlotId::Secondary => self.layout.secondary,
+        };
+        let header = match ImageHeader::read_from(self.flash, slot.addr, self.layout.image_capacity()) {
+            Ok(header) => Some(header),
+            Err(ImageError::Flash(e)) => return Err(e),
+            Err(_) => None,
+        };
+        let state = trailer::read_state(self.flash, &self.layout, slot)?;
+        Ok(SlotStatus { header, state })
+    }
+
+    /// Start streaming the image described by `header` into the secondary
+    /// slot; see `SlotLayout::begin_update`. Feed it with
+    /// `FirmwareUpdater::write_chunk`, header first, and check it with
+    /// `finalize_update`. The bootloader installs it on the next reset if it
+    /// is newer than the running image.
+    ///
+    /// Refused while the running image is on trial: the secondary slot then
+    /// holds the image the bootloader falls back to.
+    pub fn begin_update<D: ImageDigest>(
+        &mut self,
+        header: &ImageHeader,
+        counter: &dyn SecurityCounter,
+    ) -> UpdateResult<FirmwareUpdater<'_, D>> {
+        if self.on_trial()? {
+            return Err(UpdateError::Other("running image not confirmed"));
+        }
+        self.layout.begin_update(self.flash, header, counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::{ExpectedDigest, Sha256};
+    use crate::flash::MockFlash;
+    use crate::image::{ImageVersion, IMAGE_HEADER_ENCODED_LEN, IMAGE_HEADER_SIZE};
+    use crate::rollback::FlashCounter;
+    use crate::slot::Slot;
+
+    const COUNTER: FlashCounter = FlashCounter::new(Slot::new(0x0400, 0x400));
+
+    #[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x1000, 0x1000),
+        Slot::new(0x2000, 0x1000),
+        Slot::new(0x3000, 0x400),
+        Slot::new(0x0000, 0x400),
+    );
+
+    #[cfg(feature = "swap-move")]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x1000, 0x1400),
+        Slot::new(0x2400, 0x1000),
+        Slot::new(0, 0),
+        Slot::new(0x0000, 0x400),
+    );
+
+    #[cfg(feature = "overwrite-only")]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x1000, 0x1000),
+        Slot::new(0x2000, 0x1000),
+        Slot::new(0, 0),
+        Slot::new(0x0000, 0x400),
+    );
+
+    /// Header and image bytes for `payload`, as a download would deliver them:
+    /// signed with the development key in `secure-boot` builds.
+    fn image(version: ImageVersion, payload: &[u8]) -> (ImageHeader, Vec<u8>) {
+        let mut d = Sha256::new();
+        d.update(payload);
+        let header = ImageHeader::new(0x0800_1200, payload.len() as u32, version, ExpectedDigest::Sha256(d.finalize()));
+        let mut bytes = vec![0xFFu8; IMAGE_HEADER_SIZE + payload.len()];
+        bytes[..IMAGE_HEADER_ENCODED_LEN].copy_from_slice(&header.to_bytes());
+        bytes[IMAGE_HEADER_SIZE..].copy_from_slice(payload);
+        #[cfg(feature = "secure-boot")]
+        {
+            use crate::image::{IMAGE_SIGNATURE_LEN, IMAGE_SIGNATURE_OFFSET};
+            use crate::signature::test_keys::{sign_hash, DEV_SEED};
+
+            let mut tmp = MockFlash::new(bytes.len().next_multiple_of(0x400), 0x400, 0x100);
+            tmp.write_region(0, &bytes).unwrap();
+            let hash = crate::signature::image_hash(&tmp, 0, &header).unwrap();
+            bytes[IMAGE_SIGNATURE_OFFSET..IMAGE_SIGNATURE_OFFSET + IMAGE_SIGNATURE_LEN]
+                .copy_from_slice(&sign_hash(&DEV_SEED, &hash));
+        }
+        (header, bytes)
+    }
+
+    #[test]
+    fn slots_are_reported() {
+        let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+        let (header, bytes) = image(ImageVersion::new(1, 0, 0, 0), &[0x11; 600]);
+        f.write_region(LAYOUT.primary.addr, &bytes).unwrap();
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Testing).unwrap();
+
+        let mut client = BootClient::new(&mut f, LAYOUT);
+        assert_eq!(client.on_trial(), Ok(true));
+        assert_eq!(
+            client.slot_status(SlotId::Primary),
+            Ok(SlotStatus { header: Some(header), state: Some(ImageState::Testing) })
+        );
+        assert_eq!(client.slot_status(SlotId::Secondary), Ok(SlotStatus { header: None, state: None }));
+
+        client.confirm().unwrap();
+        assert_eq!(client.on_trial(), Ok(false));
+        assert_eq!(client.slot_status(SlotId::Primary).unwrap().state, Some(ImageState::Confirmed));
+    }
+
+    #[test]
+    fn no_update_while_on_trial() {
+        let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+        let (_, fallback) = image(ImageVersion::new(1, 0, 0, 0), &[0x11; 600]);
+        f.write_region(LAYOUT.secondary.addr, &fallback).unwrap();
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Testing).unwrap();
+        let before = f.storage.clone();
+
+        let (header, _) = image(ImageVersion::new(3, 0, 0, 0), &[0x33; 600]);
+        let mut client = BootClient::new(&mut f, LAYOUT);
+        assert!(matches!(client.begin_update::<Sha256>(&header, &COUNTER), Err(UpdateError::Other(_))));
+        assert_eq!(f.storage, before);
+    }
+
+    #[test]
+    fn streamed_image_is_installed_by_the_bootloader() {
+        let mut f = MockFlash::new(0x3400, 0x400, 0x100);
+        let (_, running) = image(ImageVersion::new(1, 0, 0, 0), &[0x11; 600]);
+        f.write_region(LAYOUT.primary.addr, &running).unwrap();
+
+        // Download in page-sized pieces while the old image keeps running.
+        let (header, bytes) = image(ImageVersion::new(2, 0, 0, 0), &[0x22; 900]);
+        {
+            let mut client = BootClient::new(&mut f, LAYOUT);
+            let mut updater = client.begin_update::<Sha256>(&header, &COUNTER).unwrap();
+            for (i, chunk) in bytes.chunks(0x100).enumerate() {
+                updater.write_chunk(i * 0x100, chunk).unwrap();
+            }
+            updater.finalize_update().unwrap();
+        }
+
+        assert_eq!(crate::boot::select_image::<Sha256>(&mut f, &LAYOUT, &COUNTER, 1, false), Ok(header));
+        let mut client = BootClient::new(&mut f, LAYOUT);
+        assert_eq!(client.on_trial(), Ok(true));
+        client.confirm().unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Put the MCU back close to its reset state before the handoff.
+//!
+//! The application's startup code assumes reset defaults: no interrupt
+//! enabled or pending, SysTick stopped, its peripherals unclocked and (with
+//! `restore_clocks`) running from the HSI. Anything the bootloader leaves
+//! behind fires into the application's vector table or trips its drivers.
+//! The bootloader's RAM may also still hold key material and digests from
+//! verification, which the application has no business reading.
+//!
+//! Everything goes through [`Mmio`], so `MockSystem` can record exactly
+//! which registers were touched. The independent watchdog cannot be
+//! stopped and is left running on purpose (see `watchdog.rs`); PRIMASK is
+//! up to the caller.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::stm32f4::{Mmio, FLASH_REGS_BASE};
+
+/// NVIC interrupt clear-enable registers.
+pub const NVIC_ICER: usize = 0xE000_E180;
+/// NVIC interrupt clear-pending registers.
+pub const NVIC_ICPR: usize = 0xE000_E280;
+/// ICER/ICPR words; 8 cover every IRQ the architecture allows.
+const NVIC_WORDS: usize = 8;
+
+/// SysTick control and status register.
+pub const SYST_CSR: usize = 0xE000_E010;
+const SYST_RVR: usize = 0xE000_E014;
+const SYST_CVR: usize = 0xE000_E018;
+
+/// Interrupt control and state register.
+pub const SCB_ICSR: usize = 0xE000_ED04;
+const ICSR_PENDSTCLR: u32 = 1 << 25;
+const ICSR_PENDSVCLR: u32 = 1 << 27;
+
+/// Base address of the RCC registers.
+pub const RCC_BASE: usize = 0x4002_3800;
+const RCC_CR: usize = RCC_BASE;
+const RCC_PLLCFGR: usize = RCC_BASE + 0x04;
+const RCC_CFGR: usize = RCC_BASE + 0x08;
+const RCC_CIR: usize = RCC_BASE + 0x0C;
+const RCC_AHB1RSTR: usize = RCC_BASE + 0x10;
+const RCC_AHB2RSTR: usize = RCC_BASE + 0x14;
+const RCC_APB1RSTR: usize = RCC_BASE + 0x20;
+const RCC_APB2RSTR: usize = RCC_BASE + 0x24;
+const RCC_AHB1ENR: usize = RCC_BASE + 0x30;
+const RCC_AHB2ENR: usize = RCC_BASE + 0x34;
+const RCC_APB1ENR: usize = RCC_BASE + 0x40;
+const RCC_APB2ENR: usize = RCC_BASE + 0x44;
+
+const FLASH_ACR: usize = FLASH_REGS_BASE;
+
+const CR_HSION: u32 = 1 << 0;
+const CR_HSIRDY: u32 = 1 << 1;
+const CR_HSEON: u32 = 1 << 16;
+#[cfg(any(test, feature = "std"))]
+const CR_HSERDY: u32 = 1 << 17;
+const CR_HSEBYP: u32 = 1 << 18;
+const CR_CSSON: u32 = 1 << 19;
+const CR_PLLON: u32 = 1 << 24;
+#[cfg(any(test, feature = "std"))]
+const CR_PLLRDY: u32 = 1 << 25;
+const CR_PLLI2SON: u32 = 1 << 26;
+#[cfg(any(test, feature = "std"))]
+const CR_PLLI2SRDY: u32 = 1 << 27;
+
+#[cfg(any(test, feature = "std"))]
+const CFGR_SW_MASK: u32 = 0b11;
+const CFGR_SWS_SHIFT: u32 = 2;
+const CFGR_SWS_MASK: u32 = 0b11 << CFGR_SWS_SHIFT;
+
+/// Reset value of RCC_PLLCFGR.
+const PLLCFGR_RESET: u32 = 0x2400_3010;
+/// Clears every clock ready and CSS flag; all clock interrupts disabled.
+const CIR_CLEAR: u32 = 0x00BF_0000;
+
+/// Polls of a ready flag before the clock is declared stuck.
+const CLOCK_POLLS: u32 = 100_000;
+
+/// Errors while deinitializing.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeinitError {
+    /// The HSI did not come up or SYSCLK did not switch to it.
+    ClockTimeout,
+}
+
+impl fmt::Display for DeinitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeinitError::ClockTimeout => write!(f, "deinit: clock switch to HSI timed out"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, DeinitError>;
+
+/// RCC reset/enable bits of peripherals, one mask per bus. The bit numbers
+/// are the same in the xRSTR and xENR registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Peripherals {
+    pub ahb1: u32,
+    pub ahb2: u32,
+    pub apb1: u32,
+    pub apb2: u32,
+}
+
+/// What [`deinit`] undoes.
+#[derive(Debug, Clone, Copy)]
+pub struct DeinitConfig<'a> {
+    /// Peripherals the bootloader brought up; reset and unclocked.
+    pub peripherals: Peripherals,
+    /// Switch SYSCLK back to the HSI and turn the HSE and PLLs off.
+    pub restore_clocks: bool,
+    /// RAM to zero, rounded inward to whole words. Must not include the
+    /// live stack of the caller.
+    pub scrub: &'a [Range<usize>],
+}
+
+/// Disable and clear every interrupt, stop SysTick, reset the bootloader's
+/// peripherals, optionally restore the clock tree, then scrub RAM.
+pub fn deinit<M: Mmio>(mmio: &mut M, config: &DeinitConfig) -> Result<()> {
+    for word in 0..NVIC_WORDS {
+        mmio.write32(NVIC_ICER + word * 4, u32::MAX);
+    }
+    for word in 0..NVIC_WORDS {
+        mmio.write32(NVIC_ICPR + word * 4, u32::MAX);
+    }
+
+    mmio.write32(SYST_CSR, 0);
+    mmio.write32(SYST_RVR, 0);
+    mmio.write32(SYST_CVR, 0);
+    mmio.write32(SCB_ICSR, ICSR_PENDSTCLR | ICSR_PENDSVCLR);
+
+    let p = config.peripherals;
+    for (rstr, enr, mask) in [
+        (RCC_AHB1RSTR, RCC_AHB1ENR, p.ahb1),
+        (RCC_AHB2RSTR, RCC_AHB2ENR, p.ahb2),
+        (RCC_APB1RSTR, RCC_APB1ENR, p.apb1),
+        (RCC_APB2RSTR, RCC_APB2ENR, p.apb2),
+    ] {
+        if mask != 0 {
+            let held = mmio.read32(rstr);
+            mmio.write32(rstr, held | mask);
+            mmio.write32(rstr, held & !mask);
+            let enabled = mmio.read32(enr);
+            mmio.write32(enr, enabled & !mask);
+        }
+    }
+
+    if config.restore_clocks {
+        restore_clocks(mmio)?;
+    }
+
+    for range in config.scrub {
+        let mut addr = range.start.next_multiple_of(4);
+        while addr + 4 <= range.end {
+            mmio.write32(addr, 0);
+            addr += 4;
+        }
+    }
+    Ok(())
+}
+
+/// Back to the reset clock tree: SYSCLK from the 16 MHz HSI, bus prescalers
+/// at 1, HSE and PLLs off, zero flash wait states.
+fn restore_clocks<M: Mmio>(mmio: &mut M) -> Result<()> {
+    let cr = mmio.read32(RCC_CR);
+    mmio.write32(RCC_CR, cr | CR_HSION);
+    wait(mmio, RCC_CR, CR_HSIRDY, CR_HSIRDY)?;
+
+    mmio.write32(RCC_CFGR, 0);
+    wait(mmio, RCC_CFGR, CFGR_SWS_MASK, 0)?;
+
+    // HSEBYP may only change once the HSE is off.
+    let cr = mmio.read32(RCC_CR);
+    mmio.write32(RCC_CR, cr & !(CR_HSEON | CR_CSSON | CR_PLLON | CR_PLLI2SON));
+    let cr = mmio.read32(RCC_CR);
+    mmio.write32(RCC_CR, cr & !CR_HSEBYP);
+    mmio.write32(RCC_PLLCFGR, PLLCFGR_RESET);
+    mmio.write32(RCC_CIR, CIR_CLEAR);
+
+    // Only now that SYSCLK is 16 MHz.
+    mmio.write32(FLASH_ACR, 0);
+    Ok(())
+}
+
+fn wait<M: Mmio>(mmio: &M, reg: usize, mask: u32, expected: u32) -> Result<()> {
+    for _ in 0..CLOCK_POLLS {
+        if mmio.read32(reg) & mask == expected {
+            return Ok(());
+        }
+    }
+    Err(DeinitError::ClockTimeout)
+}
+
+/// Register file standing in for the core peripherals, RCC, the flash
+/// interface and RAM. Every write is logged in order; the RCC ready and
+/// switch status bits follow their enable and select bits.
+#[cfg(any(test, feature = "std"))]
+#[derive(Debug, Default)]
+pub struct MockSystem {
+    /// Current register values, preset or last written.
+    pub regs: Vec<(usize, u32)>,
+    /// Every `write32`, in order.
+    pub writes: Vec<(usize, u32)>,
+    /// Oscillators never become ready and the clock switch never happens.
+    pub stuck_clocks: bool,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl MockSystem {
+    pub fn get(&self, addr: usize) -> u32 {
+        self.regs.iter().rev().find(|(a, _)| *a == addr).map_or(0, |&(_, v)| v)
+    }
+
+    pub fn set(&mut self, addr: usize, val: u32) {
+        self.regs.retain(|(a, _)| *a != addr);
+        self.regs.push((addr, val));
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Mmio for MockSystem {
+    fn read8(&self, addr: usize) -> u8 {
+        panic!("mock: unmapped read8 at {:#010x}", addr)
+    }
+
+    fn read32(&self, addr: usize) -> u32 {
+        let val = self.get(addr);
+        match addr {
+            RCC_CR if !self.stuck_clocks => {
+                let ready = (val & CR_HSION) << 1
+                    | (val & CR_HSEON) << 1
+                    | (val & CR_PLLON) << 1
+                    | (val & CR_PLLI2SON) << 1;
+                val & !(CR_HSIRDY | CR_HSERDY | CR_PLLRDY | CR_PLLI2SRDY) | ready
+            }
+            RCC_CFGR if !self.stuck_clocks => val & !CFGR_SWS_MASK | (val & CFGR_SW_MASK) << CFGR_SWS_SHIFT,
+            _ => val,
+        }
+    }
+
+    fn write8(&mut self, addr: usize, _val: u8) {
+        panic!("mock: unmapped write8 at {:#010x}", addr)
+    }
+
+    fn write16(&mut self, addr: usize, _val: u16) {
+        panic!("mock: unmapped write16 at {:#010x}", addr)
+    }
+
+    fn write32(&mut self, addr: usize, val: u32) {
+        self.writes.push((addr, val));
+        // Status bits are read-only.
+        let stored = match addr {
+            RCC_CR => val & !(CR_HSIRDY | CR_HSERDY | CR_PLLRDY | CR_PLLI2SRDY),
+            RCC_CFGR => val & !CFGR_SWS_MASK,
+            _ => val,
+        };
+        self.set(addr, stored);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USART1: u32 = 1 << 4;
+    const GPIOA: u32 = 1 << 0;
+
+    #[test]
+    fn only_the_expected_registers_are_touched() {
+        let mut mock = MockSystem::default();
+        mock.set(RCC_AHB1ENR, GPIOA | 1 << 12);
+        mock.set(RCC_APB2ENR, USART1);
+        let config = DeinitConfig {
+            peripherals: Peripherals { ahb1: GPIOA, apb2: USART1, ..Default::default() },
+            restore_clocks: false,
+            scrub: &[],
+        };
+        deinit(&mut mock, &config).unwrap();
+
+        let mut expected: Vec<(usize, u32)> = (0..8).map(|i| (NVIC_ICER + i * 4, u32::MAX)).collect();
+        expected.extend((0..8).map(|i| (NVIC_ICPR + i * 4, u32::MAX)));
+        expected.extend([
+            (SYST_CSR, 0),
+            (SYST_RVR, 0),
+            (SYST_CVR, 0),
+            (SCB_ICSR, ICSR_PENDSTCLR | ICSR_PENDSVCLR),
+            (RCC_AHB1RSTR, GPIOA),
+            (RCC_AHB1RSTR, 0),
+            (RCC_AHB1ENR, 1 << 12),
+            (RCC_APB2RSTR, USART1),
+            (RCC_APB2RSTR, 0),
+            (RCC_APB2ENR, 0),
+        ]);
+        assert_eq!(mock.writes, expected);
+    }
+
+    #[test]
+    fn clock_tree_is_restored() {
+        let mut mock = MockSystem::default();
+        mock.set(RCC_CR, CR_HSEON | CR_HSEBYP | CR_CSSON | CR_PLLON);
+        mock.set(RCC_CFGR, 0b10 | 0b100 << 10);
+        mock.set(RCC_PLLCFGR, 0x0740_5408);
+        mock.set(FLASH_ACR, 0x0703);
+        let config = DeinitConfig { peripherals: Peripherals::default(), restore_clocks: true, scrub: &[] };
+        deinit(&mut mock, &config).unwrap();
+
+        assert_eq!(mock.get(RCC_CR), CR_HSION);
+        assert_eq!(mock.get(RCC_CFGR), 0);
+        assert_eq!(mock.get(RCC_PLLCFGR), PLLCFGR_RESET);
+        assert_eq!(mock.get(RCC_CIR), CIR_CLEAR);
+        assert_eq!(mock.get(FLASH_ACR), 0);
+        // SYSCLK left the PLL before the PLL was turned off.
+        let switched = mock.writes.iter().position(|&w| w == (RCC_CFGR, 0)).unwrap();
+        let pll_off = mock.writes.iter().position(|&(a, v)| a == RCC_CR && v & CR_PLLON == 0).unwrap();
+        assert!(switched < pll_off);
+
+        let mut mock = MockSystem { stuck_clocks: true, ..Default::default() };
+        assert_eq!(deinit(&mut mock, &config), Err(DeinitError::ClockTimeout));
+    }
+
+    #[test]
+    fn ram_is_scrubbed_in_whole_words() {
+        let mut mock = MockSystem::default();
+        let config = DeinitConfig {
+            peripherals: Peripherals::default(),
+            restore_clocks: false,
+            scrub: &[0x2000_0102..0x2000_0112, 0x2000_4000..0x2000_4008],
+        };
+        deinit(&mut mock, &config).unwrap();
+
+        let sram = 0x2000_0000..0x4000_0000;
+        let ram: Vec<(usize, u32)> = mock.writes.into_iter().filter(|(a, _)| sram.contains(a)).collect();
+        assert_eq!(
+            ram,
+            [(0x2000_0104, 0), (0x2000_0108, 0), (0x2000_010C, 0), (0x2000_4000, 0), (0x2000_4004, 0)]
+        );
+    }
+}
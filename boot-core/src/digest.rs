@@ -0,0 +1,250 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Pluggable image digest algorithms.
+//!
+//! The [`ImageDigest`] trait lets the verification and update paths be
+//! generic over the algorithm used to check an image, so low-end boards can
+//! keep a cheap CRC32 while secure products use a strong hash with the same
+//! code. Only the algorithms a build actually instantiates end up in flash.
+//!
+//! Supported algorithms: CRC32 (IEEE), SHA-256, SHA-512 and BLAKE2s-256.
+//! [`ExpectedDigest`] is the tagged value stored in image headers and
+//! update metadata.
+
+use core::fmt;
+
+use sha2::digest::Digest;
+
+/// Largest digest output of all supported algorithms (SHA-512).
+pub const MAX_DIGEST_LEN: usize = 64;
+
+/// Identifier of a digest algorithm, as stored in the image header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DigestType {
+    /// CRC32 (IEEE), output stored little-endian.
+    Crc32 = 1,
+    Sha256 = 2,
+    Sha512 = 3,
+    /// BLAKE2s with a 256-bit output.
+    Blake2s = 4,
+}
+
+impl DigestType {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(DigestType::Crc32),
+            2 => Some(DigestType::Sha256),
+            3 => Some(DigestType::Sha512),
+            4 => Some(DigestType::Blake2s),
+            _ => None,
+        }
+    }
+
+    /// Digest output length in bytes.
+    pub const fn output_len(self) -> usize {
+        match self {
+            DigestType::Crc32 => 4,
+            DigestType::Sha256 => 32,
+            DigestType::Sha512 => 64,
+            DigestType::Blake2s => 32,
+        }
+    }
+}
+
+impl fmt::Display for DigestType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            DigestType::Crc32 => "crc32",
+            DigestType::Sha256 => "sha256",
+            DigestType::Sha512 => "sha512",
+            DigestType::Blake2s => "blake2s",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Expected digest of an image, tagged with its algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectedDigest {
+    Crc32(u32),
+    Sha256([u8; 32]),
+    Sha512([u8; 64]),
+    Blake2s([u8; 32]),
+}
+
+impl ExpectedDigest {
+    pub fn digest_type(&self) -> DigestType {
+        match self {
+            ExpectedDigest::Crc32(_) => DigestType::Crc32,
+            ExpectedDigest::Sha256(_) => DigestType::Sha256,
+            ExpectedDigest::Sha512(_) => DigestType::Sha512,
+            ExpectedDigest::Blake2s(_) => DigestType::Blake2s,
+        }
+    }
+
+    /// Compare against a computed digest output.
+    pub fn matches(&self, computed: &[u8]) -> bool {
+        match self {
+            ExpectedDigest::Crc32(crc) => computed == crc.to_le_bytes(),
+            ExpectedDigest::Sha256(d) | ExpectedDigest::Blake2s(d) => computed == d,
+            ExpectedDigest::Sha512(d) => computed == d,
+        }
+    }
+
+    /// Encode into a fixed-size, zero-padded digest field.
+    pub fn to_field(&self) -> [u8; MAX_DIGEST_LEN] {
+        let mut out = [0u8; MAX_DIGEST_LEN];
+        match self {
+            ExpectedDigest::Crc32(crc) => out[..4].copy_from_slice(&crc.to_le_bytes()),
+            ExpectedDigest::Sha256(d) | ExpectedDigest::Blake2s(d) => out[..32].copy_from_slice(d),
+            ExpectedDigest::Sha512(d) => out.copy_from_slice(d),
+        }
+        out
+    }
+
+    /// Decode from a digest field written by [`ExpectedDigest::to_field`].
+    pub fn from_field(digest_type: DigestType, field: &[u8; MAX_DIGEST_LEN]) -> Self {
+        let mut d32 = [0u8; 32];
+        d32.copy_from_slice(&field[..32]);
+        match digest_type {
+            DigestType::Crc32 => {
+                ExpectedDigest::Crc32(u32::from_le_bytes([field[0], field[1], field[2], field[3]]))
+            }
+            DigestType::Sha256 => ExpectedDigest::Sha256(d32),
+            DigestType::Sha512 => ExpectedDigest::Sha512(*field),
+            DigestType::Blake2s => ExpectedDigest::Blake2s(d32),
+        }
+    }
+}
+
+/// A digest algorithm usable by the verification and update paths.
+pub trait ImageDigest {
+    /// Algorithm identifier matching [`ExpectedDigest::digest_type`].
+    const TYPE: DigestType;
+    /// Digest output, `TYPE.output_len()` bytes long.
+    type Output: AsRef<[u8]>;
+
+    fn new() -> Self;
+    fn update(&mut self, data: &[u8]);
+    fn finalize(self) -> Self::Output;
+}
+
+/// CRC32 (IEEE) "digest". Not a cryptographic hash: it only detects
+/// accidental corruption.
+pub struct Crc32(crc_any::CRCu32);
+
+impl ImageDigest for Crc32 {
+    const TYPE: DigestType = DigestType::Crc32;
+    type Output = [u8; 4];
+
+    fn new() -> Self {
+        Crc32(crc_any::CRCu32::crc32())
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        self.0.digest(data);
+    }
+
+    fn finalize(self) -> [u8; 4] {
+        self.0.get_crc().to_le_bytes()
+    }
+}
+
+macro_rules! rust_crypto_digest {
+    ($(#[$doc:meta])* $name:ident, $inner:ty, $ty:expr, $len:expr) => {
+        $(#[$doc])*
+        pub struct $name($inner);
+
+        impl ImageDigest for $name {
+            const TYPE: DigestType = $ty;
+            type Output = [u8; $len];
+
+            fn new() -> Self {
+                $name(<$inner as Digest>::new())
+            }
+
+            fn update(&mut self, data: &[u8]) {
+                Digest::update(&mut self.0, data);
+            }
+
+            fn finalize(self) -> [u8; $len] {
+                Digest::finalize(self.0).into()
+            }
+        }
+    };
+}
+
+rust_crypto_digest!(
+    /// SHA-256.
+    Sha256, sha2::Sha256, DigestType::Sha256, 32
+);
+rust_crypto_digest!(
+    /// SHA-512.
+    Sha512, sha2::Sha512, DigestType::Sha512, 64
+);
+rust_crypto_digest!(
+    /// BLAKE2s with a 256-bit output.
+    Blake2s, blake2::Blake2s256, DigestType::Blake2s, 32
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn digest_of<D: ImageDigest>(data: &[u8]) -> Vec<u8> {
+        let mut d = D::new();
+        // Split the input to exercise incremental updates.
+        let (a, b) = data.split_at(data.len() / 2);
+        d.update(a);
+        d.update(b);
+        let out = d.finalize();
+        assert_eq!(out.as_ref().len(), D::TYPE.output_len());
+        out.as_ref().to_vec()
+    }
+
+    #[test]
+    fn known_answers_for_abc() {
+        assert_eq!(digest_of::<Crc32>(b"123456789"), 0xCBF4_3926u32.to_le_bytes());
+        assert_eq!(
+            digest_of::<Sha256>(b"abc"),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            digest_of::<Sha512>(b"abc"),
+            hex("ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f")
+        );
+        assert_eq!(
+            digest_of::<Blake2s>(b"abc"),
+            hex("508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982")
+        );
+    }
+
+    #[test]
+    fn expected_digest_field_roundtrip() {
+        let values = [
+            ExpectedDigest::Crc32(0xDEAD_BEEF),
+            ExpectedDigest::Sha256([0x11; 32]),
+            ExpectedDigest::Sha512([0x22; 64]),
+            ExpectedDigest::Blake2s([0x33; 32]),
+        ];
+        for v in values {
+            let field = v.to_field();
+            assert_eq!(ExpectedDigest::from_field(v.digest_type(), &field), v);
+        }
+        assert!(ExpectedDigest::Crc32(0xCBF4_3926).matches(&digest_of::<Crc32>(b"123456789")));
+        assert!(!ExpectedDigest::Sha256([0; 32]).matches(&digest_of::<Sha256>(b"abc")));
+    }
+}
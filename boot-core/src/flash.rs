@@ -0,0 +1,789 @@
+//! M2 Bootloader RUST 
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! boot-core/src/flash.rs
+//! Flash abstraction and utility functions for the bootloader.
+//!
+//! This module provides:
+//! - A generic `Flash` trait that the rest of the bootloader uses.
+//! - A `MockFlash` in-memory implementation useful for testing and host-side
+//!   unit-tests.
+//!
+//! The STM32F4 on-chip driver (`InternalFlash`) lives in `stm32f4.rs`. The
+//! bootloader binary owns the static instance and the
+//! `read_flash`/`write_flash` helpers built on it (`board.rs`).
+//!
+//! Features and notes:
+//! - The module is testable on host using the `MockFlash` type, which is
+//!   only built for tests or with the `std` feature.
+//! - `MockFlash` can simulate power loss: armed with `cut_power_after`, it
+//!   tears the chosen erase or program operation (a partially erased sector
+//!   or partially programmed page, with random bits) and refuses all access
+//!   until `reboot`, which keeps the storage. Tests use it to cut power at
+//!   every step of a flash sequence.
+//! - `MockFlash` counts erase cycles per sector and program operations per
+//!   page, and can refuse erases past an endurance limit (`set_endurance`),
+//!   so tests can check that state records and swaps spread their wear.
+//! - `MockFlash` can flip bits, at given addresses or at random, either in
+//!   the data returned by reads or in storage once a program completes, so
+//!   tests can show the verification layers catch corrupted flash.
+//! - The default `verify` and `crc32` trait methods read through small stack
+//!   buffers, so they behave the same under `no_std` (no allocator) and `std`.
+
+#![allow(dead_code)]
+
+use core::fmt;
+
+#[cfg(any(test, feature = "std"))]
+use std::{vec, vec::Vec};
+
+/// Default page size used by mock devices and as a hint for internal drivers.
+pub const DEFAULT_PAGE_SIZE: usize = 256;
+
+/// Size of the stack buffer used by the default `verify` and `crc32`
+/// implementations.
+pub const READ_CHUNK_SIZE: usize = 256;
+
+/// Errors returned by flash operations.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FlashError {
+    OutOfBounds,
+    AlignmentError,
+    DeviceError(&'static str),
+    VerificationFailed { addr: usize, expected: u8, found: u8 },
+    /// The controller stayed busy past the driver's timeout.
+    Timeout,
+    /// The unlock key sequence was rejected.
+    Locked,
+    /// Erase or program targeted a write-protected sector (WRPERR).
+    WriteProtected,
+    /// Program address not aligned to the program size (PGAERR).
+    ProgramAlignment,
+    /// Program access width differs from the configured PSIZE (PGPERR).
+    ProgramParallelism,
+    /// Flash written outside a program sequence (PGSERR).
+    ProgramSequence,
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::OutOfBounds => write!(f, "flash: address out of bounds"),
+            FlashError::AlignmentError => write!(f, "flash: alignment error"),
+            FlashError::DeviceError(s) => write!(f, "flash device error: {}", s),
+            FlashError::VerificationFailed { addr, expected, found } => write!(
+                f,
+                "flash verify failed at {:#010x}: expected=0x{:02x} found=0x{:02x}",
+                addr, expected, found
+            ),
+            FlashError::Timeout => write!(f, "flash: operation timed out"),
+            FlashError::Locked => write!(f, "flash: controller locked"),
+            FlashError::WriteProtected => write!(f, "flash: sector write-protected"),
+            FlashError::ProgramAlignment => write!(f, "flash: program alignment error"),
+            FlashError::ProgramParallelism => write!(f, "flash: program parallelism error"),
+            FlashError::ProgramSequence => write!(f, "flash: program sequence error"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FlashError {}
+
+pub type Result<T> = core::result::Result<T, FlashError>;
+
+/// A run of `count` equally sized erase sectors. A device's sector map is
+/// a list of these in address order, e.g. 4x16 KiB, 1x64 KiB, 3x128 KiB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorRegion {
+    pub count: usize,
+    pub size: usize,
+}
+
+impl SectorRegion {
+    pub const fn new(count: usize, size: usize) -> Self {
+        Self { count, size }
+    }
+}
+
+/// One erase sector resolved from a sector map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sector {
+    /// Sector number counted from the start of the device.
+    pub index: usize,
+    pub start: usize,
+    pub size: usize,
+}
+
+impl Sector {
+    /// First address past the sector.
+    pub fn end(&self) -> usize {
+        self.start + self.size
+    }
+}
+
+/// Total size in bytes described by a sector map.
+pub const fn map_size(map: &[SectorRegion]) -> usize {
+    let mut total = 0;
+    let mut i = 0;
+    while i < map.len() {
+        total += map[i].count * map[i].size;
+        i += 1;
+    }
+    total
+}
+
+/// Iterate over every sector of a map in address order.
+pub fn sectors(map: &[SectorRegion]) -> impl Iterator<Item = Sector> + '_ {
+    map.iter()
+        .flat_map(|r| core::iter::repeat_n(r.size, r.count))
+        .scan(0usize, |start, size| {
+            let sector_start = *start;
+            *start += size;
+            Some((sector_start, size))
+        })
+        .enumerate()
+        .map(|(index, (start, size))| Sector { index, start, size })
+}
+
+/// Wear of one sector of a [`MockFlash`].
+#[cfg(any(test, feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorWear {
+    pub sector: Sector,
+    /// Erase cycles, torn ones included.
+    pub erases: u32,
+    /// Program operations on the sector's pages.
+    pub programs: u32,
+}
+
+/// When an injected [`MockFlash`] bit flip strikes.
+#[cfg(any(test, feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlipOn {
+    /// In the data returned by every read, storage stays intact (read
+    /// disturb, a weak cell).
+    Read,
+    /// In storage, once a program of the byte completes (a bad program or
+    /// a retention error).
+    Program,
+}
+
+/// Trait for flash devices used by the bootloader. Keep implementation minimal
+/// to allow both on-chip flash and external SPI/NOR devices to implement it.
+///
+/// Erase geometry comes from `sector_map`; sectors need not be uniform.
+pub trait Flash {
+    fn size(&self) -> usize;
+    fn sector_map(&self) -> &[SectorRegion];
+    fn page_size(&self) -> usize;
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()>;
+    fn erase_sector(&mut self, addr: usize) -> Result<()>;
+    fn program_page(&mut self, addr: usize, data: &[u8]) -> Result<()>;
+
+    /// The sector containing `addr`, or `None` past the end of the device.
+    fn sector_at(&self, addr: usize) -> Option<Sector> {
+        sectors(self.sector_map()).find(|s| addr < s.end())
+    }
+
+    /// Default verify implementation: reads back in `READ_CHUNK_SIZE` chunks
+    /// into a stack buffer and compares, reporting the first mismatch.
+    fn verify(&self, addr: usize, data: &[u8]) -> Result<()> {
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        let mut offset = 0usize;
+        while offset < data.len() {
+            let chunk = core::cmp::min(buf.len(), data.len() - offset);
+            self.read(addr + offset, &mut buf[..chunk])?;
+            let expected = &data[offset..offset + chunk];
+            for (i, (&a, &b)) in expected.iter().zip(buf[..chunk].iter()).enumerate() {
+                if a != b {
+                    return Err(FlashError::VerificationFailed {
+                        addr: addr + offset + i,
+                        expected: a,
+                        found: b,
+                    });
+                }
+            }
+            offset += chunk;
+        }
+        Ok(())
+    }
+
+    /// Erase every sector overlapping `addr..addr + len`. Data sharing a
+    /// sector with the range is erased too.
+    fn erase_range(&mut self, addr: usize, len: usize) -> Result<()> {
+        if addr.checked_add(len).is_none() || addr + len > self.size() {
+            return Err(FlashError::OutOfBounds);
+        }
+        let end = addr + len;
+        let mut pos = addr;
+        while pos < end {
+            let sector = self.sector_at(pos).ok_or(FlashError::OutOfBounds)?;
+            self.erase_sector(sector.start)?;
+            pos = sector.end();
+        }
+        Ok(())
+    }
+
+    /// Program an already erased region page-by-page, verifying each page.
+    fn program_region(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        if addr.checked_add(data.len()).is_none() || addr + data.len() > self.size() {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let page = self.page_size();
+        if page == 0 {
+            return Err(FlashError::DeviceError("invalid page size"));
+        }
+
+        let mut offset = 0usize;
+        while offset < data.len() {
+            let write_addr = addr + offset;
+            let remain = data.len() - offset;
+            let write_len = core::cmp::min(remain, page);
+            let page_slice = &data[offset..offset + write_len];
+
+            self.program_page(write_addr, page_slice)?;
+            self.verify(write_addr, page_slice)?;
+
+            offset += write_len;
+        }
+
+        Ok(())
+    }
+
+    /// Write a region: erase affected sectors and program page-by-page.
+    fn write_region(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        self.erase_range(addr, data.len())?;
+        self.program_region(addr, data)
+    }
+
+    /// Compute the CRC32 (IEEE) of a region. The default implementation
+    /// streams the region through a stack buffer using `crc-any`.
+    fn crc32(&self, addr: usize, len: usize) -> Result<u32> {
+        if addr.checked_add(len).is_none() || addr + len > self.size() {
+            return Err(FlashError::OutOfBounds);
+        }
+
+        let mut crc = crc_any::CRCu32::crc32();
+        let mut buf = [0u8; READ_CHUNK_SIZE];
+        let mut offset = 0usize;
+        while offset < len {
+            let chunk = core::cmp::min(buf.len(), len - offset);
+            self.read(addr + offset, &mut buf[..chunk])?;
+            crc.digest(&buf[..chunk]);
+            offset += chunk;
+        }
+        Ok(crc.get_crc())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// MockFlash - in-memory implementation
+// -----------------------------------------------------------------------------
+
+#[cfg(any(test, feature = "std"))]
+pub struct MockFlash {
+    pub storage: Vec<u8>,
+    sectors: Vec<SectorRegion>,
+    page_size: usize,
+    /// Erase and program operations completed since power-up.
+    ops: usize,
+    /// Operation number to tear, if power loss is armed.
+    cut_at: Option<usize>,
+    powered: bool,
+    /// xorshift32 state for torn operations and random bit flips.
+    noise: core::cell::Cell<u32>,
+    /// Erase cycles per sector index.
+    erase_counts: Vec<u32>,
+    /// Program operations per page.
+    program_counts: Vec<u32>,
+    /// Erase cycles a sector survives, if limited.
+    endurance: Option<u32>,
+    /// Bits flipped on every read, as (address, mask).
+    read_flips: Vec<(usize, u8)>,
+    /// Bits flipped in storage by the next program of the address.
+    program_flips: Vec<(usize, u8)>,
+    /// One in this many bytes read gets a random bit flipped.
+    random_read_flips: Option<u32>,
+    /// One in this many bytes programmed gets a random bit flipped.
+    random_program_flips: Option<u32>,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl MockFlash {
+    /// Error returned by the torn operation and by every access after it,
+    /// until [`MockFlash::reboot`].
+    pub const POWER_LOSS: FlashError = FlashError::DeviceError("power lost");
+
+    /// Error returned by an erase of a sector past its endurance limit.
+    pub const WORN_OUT: FlashError = FlashError::DeviceError("sector worn out");
+
+    /// Device of `size` bytes with uniform `sector_size` sectors.
+    pub fn new(size: usize, sector_size: usize, page_size: usize) -> Self {
+        Self::with_sectors(&[SectorRegion::new(size / sector_size, sector_size)], page_size)
+    }
+
+    /// Device with an arbitrary sector map, e.g. a real part's geometry.
+    pub fn with_sectors(map: &[SectorRegion], page_size: usize) -> Self {
+        let size = map_size(map);
+        MockFlash {
+            storage: vec![0xFFu8; size],
+            sectors: map.to_vec(),
+            page_size,
+            ops: 0,
+            cut_at: None,
+            powered: true,
+            noise: core::cell::Cell::new(0x2545_F491),
+            erase_counts: vec![0; sectors(map).count()],
+            program_counts: vec![0; size.div_ceil(page_size.max(1))],
+            endurance: None,
+            read_flips: Vec::new(),
+            program_flips: Vec::new(),
+            random_read_flips: None,
+            random_program_flips: None,
+        }
+    }
+
+    pub fn fill(&mut self, v: u8) {
+        for b in self.storage.iter_mut() {
+            *b = v;
+        }
+    }
+
+    /// Lose power during an erase or program operation: the next `ops`
+    /// operations complete, the one after is torn. A torn erase sets a random
+    /// subset of the sector's bits, a torn program clears a random subset of
+    /// the bits it should have cleared.
+    pub fn cut_power_after(&mut self, ops: usize) {
+        self.cut_at = Some(self.ops + ops);
+    }
+
+    /// Restore power after a cut. The storage is kept as the cut left it;
+    /// the operation count restarts and power loss is disarmed.
+    pub fn reboot(&mut self) {
+        self.ops = 0;
+        self.cut_at = None;
+        self.powered = true;
+    }
+
+    /// Erase and program operations completed since power-up, e.g. to find
+    /// how many cut points a sequence has.
+    pub fn ops(&self) -> usize {
+        self.ops
+    }
+
+    pub fn is_powered(&self) -> bool {
+        self.powered
+    }
+
+    /// Limit each sector to `cycles` erases; the erase after that fails with
+    /// [`MockFlash::WORN_OUT`] and leaves the sector as it was. The STM32F4
+    /// is rated for 10k cycles.
+    pub fn set_endurance(&mut self, cycles: Option<u32>) {
+        self.endurance = cycles;
+    }
+
+    /// Erase cycles of the sector containing `addr`.
+    pub fn erase_count(&self, addr: usize) -> u32 {
+        self.sector_at(addr).map_or(0, |s| self.erase_counts[s.index])
+    }
+
+    /// Program operations on the page containing `addr`.
+    pub fn program_count(&self, addr: usize) -> u32 {
+        self.program_counts.get(addr / self.page_size).copied().unwrap_or(0)
+    }
+
+    /// The `n` most erased sectors, most erased first.
+    pub fn hottest_sectors(&self, n: usize) -> Vec<SectorWear> {
+        let mut wear: Vec<SectorWear> = sectors(&self.sectors)
+            .map(|sector| {
+                let pages = sector.start / self.page_size..sector.end().div_ceil(self.page_size);
+                SectorWear {
+                    sector,
+                    erases: self.erase_counts[sector.index],
+                    programs: self.program_counts[pages].iter().sum(),
+                }
+            })
+            .collect();
+        // Stable, so equally worn sectors stay in address order.
+        wear.sort_by_key(|w| core::cmp::Reverse(w.erases));
+        wear.truncate(n);
+        wear
+    }
+
+    /// Forget the wear counted so far, e.g. after setting up a test.
+    pub fn reset_wear(&mut self) {
+        self.erase_counts.iter_mut().for_each(|c| *c = 0);
+        self.program_counts.iter_mut().for_each(|c| *c = 0);
+    }
+
+    /// Flip `bit` of the byte at `addr`. A `Read` flip stays until
+    /// [`MockFlash::clear_bit_flips`], a `Program` flip strikes once.
+    pub fn flip_bit(&mut self, addr: usize, bit: u8, on: FlipOn) {
+        let flip = (addr, 1u8 << (bit & 7));
+        match on {
+            FlipOn::Read => self.read_flips.push(flip),
+            FlipOn::Program => self.program_flips.push(flip),
+        }
+    }
+
+    /// Flip a random bit in one of every `one_in` bytes read or programmed,
+    /// drawn from the generator [`MockFlash::seed_noise`] seeds.
+    pub fn flip_random_bits(&mut self, on: FlipOn, one_in: u32) {
+        let rate = Some(one_in.max(1));
+        match on {
+            FlipOn::Read => self.random_read_flips = rate,
+            FlipOn::Program => self.random_program_flips = rate,
+        }
+    }
+
+    /// Stop injecting bit flips. Flips already made in storage stay.
+    pub fn clear_bit_flips(&mut self) {
+        self.read_flips.clear();
+        self.program_flips.clear();
+        self.random_read_flips = None;
+        self.random_program_flips = None;
+    }
+
+    /// Seed the random bits torn operations and random flips leave behind.
+    pub fn seed_noise(&mut self, seed: u32) {
+        // xorshift never leaves zero.
+        self.noise.set(seed.max(1));
+    }
+
+    /// Account for one erase or program operation. `Ok(true)` means power
+    /// is lost during it.
+    fn begin_op(&mut self) -> Result<bool> {
+        if !self.powered {
+            return Err(Self::POWER_LOSS);
+        }
+        if self.cut_at == Some(self.ops) {
+            self.powered = false;
+            return Ok(true);
+        }
+        self.ops += 1;
+        Ok(false)
+    }
+
+    fn next_noise(&self) -> u32 {
+        let mut x = self.noise.get();
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise.set(x);
+        x
+    }
+
+    /// Mask flipping one random bit with a chance of one in `one_in`, else 0.
+    fn random_flip(&self, one_in: Option<u32>) -> u8 {
+        match one_in {
+            Some(n) if self.next_noise().is_multiple_of(n) => 1 << (self.next_noise() & 7),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Flash for MockFlash {
+    fn size(&self) -> usize {
+        self.storage.len()
+    }
+
+    fn sector_map(&self) -> &[SectorRegion] {
+        &self.sectors
+    }
+
+    fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        if !self.powered {
+            return Err(Self::POWER_LOSS);
+        }
+        let end = addr.checked_add(buf.len()).ok_or(FlashError::OutOfBounds)?;
+        if end > self.storage.len() {
+            return Err(FlashError::OutOfBounds);
+        }
+        buf.copy_from_slice(&self.storage[addr..end]);
+        for &(flip_addr, mask) in &self.read_flips {
+            if (addr..end).contains(&flip_addr) {
+                buf[flip_addr - addr] ^= mask;
+            }
+        }
+        for b in buf.iter_mut() {
+            *b ^= self.random_flip(self.random_read_flips);
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, addr: usize) -> Result<()> {
+        let sector = self.sector_at(addr).ok_or(FlashError::OutOfBounds)?;
+        if sector.start != addr { return Err(FlashError::AlignmentError); }
+        if self.powered && self.endurance.is_some_and(|cycles| self.erase_counts[sector.index] >= cycles) {
+            return Err(Self::WORN_OUT);
+        }
+        let torn = self.begin_op()?;
+        self.erase_counts[sector.index] += 1;
+        if torn {
+            for i in sector.start..sector.end() {
+                let noise = self.next_noise() as u8;
+                self.storage[i] |= noise;
+            }
+            return Err(Self::POWER_LOSS);
+        }
+        for b in &mut self.storage[sector.start..sector.end()] { *b = 0xFF; }
+        Ok(())
+    }
+
+    fn program_page(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        if addr >= self.storage.len() { return Err(FlashError::OutOfBounds); }
+        if data.len() > self.page_size { return Err(FlashError::AlignmentError); }
+        let end = addr + data.len();
+        if end > self.storage.len() { return Err(FlashError::OutOfBounds); }
+        let torn = self.begin_op()?;
+        self.program_counts[addr / self.page_size] += 1;
+        if torn {
+            for (i, &b) in data.iter().enumerate() {
+                let noise = self.next_noise() as u8;
+                self.storage[addr + i] &= b | noise;
+            }
+            return Err(Self::POWER_LOSS);
+        }
+        for (i, &b) in data.iter().enumerate() {
+            let dst = &mut self.storage[addr + i];
+            if (b & *dst) != b {
+                return Err(FlashError::DeviceError("attempt to program 0->1"));
+            }
+            *dst &= b;
+        }
+        let storage = &mut self.storage;
+        self.program_flips.retain(|&(flip_addr, mask)| {
+            let hit = (addr..end).contains(&flip_addr);
+            if hit {
+                storage[flip_addr] ^= mask;
+            }
+            !hit
+        });
+        for i in addr..end {
+            self.storage[i] ^= self.random_flip(self.random_program_flips);
+        }
+        Ok(())
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Unit tests for host
+// -----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MockFlash;
+
+    #[test]
+    fn mock_basic_flow() {
+        let mut f = MockFlash::new(1024, 256, 128);
+        assert_eq!(f.size(), 1024);
+
+        let addr = 256;
+        let data = vec![0xAAu8; 128];
+        f.erase_sector(256).unwrap();
+        f.program_page(addr, &data).unwrap();
+        assert!(f.verify(addr, &data).is_ok());
+
+        // attempt 0->1 should fail
+        let bad = vec![0xFFu8; 128];
+        assert!(matches!(f.program_page(addr, &bad), Err(FlashError::DeviceError(_))));
+    }
+
+    #[test]
+    fn write_region_test() {
+        let mut f = MockFlash::new(2048, 256, 128);
+        let payload = vec![0x55u8; 300];
+        assert!(f.write_region(100, &payload).is_ok());
+        assert!(f.verify(100, &payload).is_ok());
+    }
+
+    #[test]
+    fn non_uniform_sector_map() {
+        let map = [SectorRegion::new(2, 256), SectorRegion::new(1, 1024), SectorRegion::new(2, 512)];
+        let mut f = MockFlash::with_sectors(&map, 128);
+        assert_eq!(f.size(), 2560);
+        assert_eq!(f.sector_at(300), Some(Sector { index: 1, start: 256, size: 256 }));
+        assert_eq!(f.sector_at(1535), Some(Sector { index: 2, start: 512, size: 1024 }));
+        assert_eq!(f.sector_at(2100), Some(Sector { index: 4, start: 2048, size: 512 }));
+        assert_eq!(f.sector_at(2560), None);
+        assert_eq!(f.erase_sector(768), Err(FlashError::AlignmentError));
+
+        // Write across the 256 -> 1024 -> 512 boundaries; neighbours inside
+        // touched sectors are erased, the rest is kept.
+        f.fill(0x00);
+        let data = [0x5Au8; 1400];
+        f.write_region(400, &data).unwrap();
+        assert!(f.verify(400, &data).is_ok());
+        assert_eq!(f.storage[255], 0x00);
+        assert_eq!(f.storage[256], 0xFF);
+        assert_eq!(f.storage[2047], 0xFF);
+        assert_eq!(f.storage[2048], 0x00);
+    }
+
+    #[test]
+    fn chunked_verify_reports_first_mismatch() {
+        let mut f = MockFlash::new(2048, 256, 128);
+        let payload: Vec<u8> = (0..700u32).map(|i| i as u8).collect();
+        f.write_region(0, &payload).unwrap();
+        assert!(f.verify(0, &payload).is_ok());
+
+        // Mismatch past the first chunk reports its absolute address.
+        let mut wrong = payload.clone();
+        wrong[600] ^= 0xFF;
+        assert_eq!(
+            f.verify(0, &wrong),
+            Err(FlashError::VerificationFailed { addr: 600, expected: wrong[600], found: payload[600] })
+        );
+    }
+
+    #[test]
+    fn crc32_matches_reference() {
+        let mut f = MockFlash::new(2048, 256, 128);
+        f.write_region(256, b"123456789").unwrap();
+        assert_eq!(f.crc32(256, 9).unwrap(), 0xCBF4_3926);
+
+        // A region spanning several read chunks.
+        let data: Vec<u8> = (0..1500u32).map(|i| (i * 31) as u8).collect();
+        f.write_region(512, &data).unwrap();
+        let mut reference = crc_any::CRCu32::crc32();
+        reference.digest(&data);
+        assert_eq!(f.crc32(512, data.len()).unwrap(), reference.get_crc());
+
+        assert_eq!(f.crc32(2000, 100), Err(FlashError::OutOfBounds));
+    }
+
+    #[test]
+    fn power_loss_tears_one_operation() {
+        let mut f = MockFlash::new(1024, 256, 128);
+        f.fill(0x00);
+        let data = [0x5Au8; 256];
+
+        // Erase completes, the first page program is torn.
+        f.cut_power_after(1);
+        assert_eq!(f.write_region(256, &data), Err(MockFlash::POWER_LOSS));
+        assert!(!f.is_powered());
+        assert_eq!(f.read(0, &mut [0u8; 4]), Err(MockFlash::POWER_LOSS));
+        assert_eq!(f.erase_sector(0), Err(MockFlash::POWER_LOSS));
+
+        f.reboot();
+        // Only bits the program should have cleared may still be set, and
+        // not all of them are.
+        assert!(f.storage[256..384].iter().all(|&b| b & 0x5A == 0x5A));
+        assert_ne!(&f.storage[256..384], &data[..128]);
+        assert!(f.storage[384..512].iter().all(|&b| b == 0xFF));
+        assert!(f.storage[..256].iter().chain(&f.storage[512..]).all(|&b| b == 0x00));
+
+        // A torn erase leaves the old contents with random bits set.
+        f.cut_power_after(0);
+        assert_eq!(f.erase_sector(512), Err(MockFlash::POWER_LOSS));
+        f.reboot();
+        assert!(f.storage[512..768].iter().any(|&b| b != 0x00 && b != 0xFF));
+    }
+
+    #[test]
+    fn wear_is_tracked_per_sector_and_page() {
+        let mut f = MockFlash::new(1024, 256, 128);
+        f.write_region(256, &[0x11u8; 200]).unwrap();
+        f.write_region(256, &[0x22u8; 100]).unwrap();
+        f.erase_sector(768).unwrap();
+        assert_eq!(f.erase_count(300), 2);
+        assert_eq!(f.program_count(256), 2);
+        assert_eq!(f.program_count(384), 1);
+
+        let hottest = f.hottest_sectors(2);
+        assert_eq!(hottest[0], SectorWear { sector: Sector { index: 1, start: 256, size: 256 }, erases: 2, programs: 3 });
+        assert_eq!(hottest[1].sector.start, 768);
+
+        // Past its endurance a sector refuses to erase and keeps its data.
+        f.set_endurance(Some(3));
+        f.erase_sector(256).unwrap();
+        f.program_page(256, &[0x33]).unwrap();
+        assert_eq!(f.erase_sector(256), Err(MockFlash::WORN_OUT));
+        assert_eq!(f.storage[256], 0x33);
+        f.erase_sector(0).unwrap();
+
+        f.reset_wear();
+        assert_eq!(f.hottest_sectors(1)[0].erases, 0);
+    }
+
+    #[test]
+    fn bit_flips_are_injected() {
+        let mut f = MockFlash::new(2048, 256, 128);
+        let data: Vec<u8> = (0..512u32).map(|i| (i * 3) as u8).collect();
+        f.write_region(256, &data).unwrap();
+        let crc = f.crc32(256, data.len()).unwrap();
+
+        // A read flip corrupts what is read, not what is stored.
+        f.flip_bit(300, 2, FlipOn::Read);
+        let mut byte = [0u8];
+        f.read(300, &mut byte).unwrap();
+        assert_eq!(byte[0], data[44] ^ 0x04);
+        assert_eq!(f.storage[300], data[44]);
+        assert_eq!(
+            f.verify(256, &data),
+            Err(FlashError::VerificationFailed { addr: 300, expected: data[44], found: data[44] ^ 0x04 })
+        );
+        assert_ne!(f.crc32(256, data.len()).unwrap(), crc);
+        f.clear_bit_flips();
+        assert!(f.verify(256, &data).is_ok());
+
+        // A program flip lands in storage once; the program's own verify
+        // reports it.
+        f.flip_bit(1030, 7, FlipOn::Program);
+        assert!(matches!(f.write_region(1024, &data), Err(FlashError::VerificationFailed { addr: 1030, .. })));
+        assert_eq!(f.storage[1030], data[6] ^ 0x80);
+        f.write_region(1024, &data).unwrap();
+
+        // Random flips are reproducible from the seed.
+        let corrupted = || {
+            let mut f = MockFlash::new(2048, 256, 128);
+            f.write_region(0, &[0u8; 1024]).unwrap();
+            f.seed_noise(7);
+            f.flip_random_bits(FlipOn::Read, 64);
+            let mut buf = [0u8; 1024];
+            f.read(0, &mut buf).unwrap();
+            buf
+        };
+        let buf = corrupted();
+        assert!(buf.iter().any(|&b| b != 0));
+        assert_eq!(buf, corrupted());
+    }
+
+    #[test]
+    fn write_region_survives_power_loss_at_every_step() {
+        let data: Vec<u8> = (0..600u32).map(|i| (i * 7) as u8).collect();
+        let mut f = MockFlash::new(2048, 256, 128);
+        f.write_region(300, &data).unwrap();
+        let steps = f.ops();
+
+        for cut in 0..steps {
+            let mut f = MockFlash::new(2048, 256, 128);
+            f.fill(0x33);
+            f.seed_noise(cut as u32 + 1);
+            f.cut_power_after(cut);
+            assert_eq!(f.write_region(300, &data), Err(MockFlash::POWER_LOSS));
+            f.reboot();
+
+            // Sectors outside the write are untouched, and writing again
+            // after the reboot succeeds.
+            assert!(f.storage[..256].iter().chain(&f.storage[1024..]).all(|&b| b == 0x33));
+            assert!(f.verify(300, &data).is_err());
+            f.write_region(300, &data).unwrap();
+            assert!(f.verify(300, &data).is_ok());
+        }
+    }
+}
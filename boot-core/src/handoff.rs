@@ -0,0 +1,189 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Handoff to the application.
+//!
+//! The application's vector table starts right after the image header. Its
+//! first two words are the initial stack pointer and the reset handler,
+//! which the jump loads into MSP and PC. A verified digest only says the
+//! image is the one that was built; it says nothing about whether it was
+//! linked for this slot. Jumping to a stack pointer outside RAM or a reset
+//! handler outside the image faults before the application runs a single
+//! instruction, so both are checked here, on bytes read through `Flash`,
+//! before the board crate sets VTOR and branches.
+
+use core::fmt;
+use core::ops::Range;
+
+use crate::flash::{Flash, FlashError};
+use crate::image::ImageHeader;
+use crate::slot::Slot;
+
+/// System control block vector table offset register.
+pub const SCB_VTOR: usize = 0xE000_ED08;
+
+/// VTOR alignment. The table must be aligned to its size rounded up to a
+/// power of two; the STM32F411 has 16 + 86 vectors, 0x198 bytes.
+pub const VECTOR_TABLE_ALIGN: u32 = 0x200;
+
+/// SRAM of the STM32F411 (128 KiB), where the initial stack must lie.
+pub const STM32F411_RAM: Range<u32> = 0x2000_0000..0x2002_0000;
+
+/// Reasons an image is not handed control.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandoffError {
+    Flash(FlashError),
+    /// The header's load address is not where the payload sits in this slot.
+    LoadAddress { expected: u32, found: u32 },
+    /// The vector table is not aligned for VTOR.
+    Unaligned(u32),
+    /// The initial stack pointer is outside RAM or not word-aligned.
+    StackPointer(u32),
+    /// The reset handler is not a Thumb address inside the image.
+    ResetHandler(u32),
+}
+
+impl fmt::Display for HandoffError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandoffError::Flash(e) => write!(f, "handoff: {}", e),
+            HandoffError::LoadAddress { expected, found } => write!(
+                f,
+                "handoff: image linked for {:#010x}, slot payload at {:#010x}",
+                found, expected
+            ),
+            HandoffError::Unaligned(addr) => write!(f, "handoff: vector table at {:#010x} unaligned", addr),
+            HandoffError::StackPointer(sp) => write!(f, "handoff: invalid initial stack pointer {:#010x}", sp),
+            HandoffError::ResetHandler(pc) => write!(f, "handoff: invalid reset handler {:#010x}", pc),
+        }
+    }
+}
+
+impl From<FlashError> for HandoffError {
+    fn from(e: FlashError) -> Self {
+        HandoffError::Flash(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, HandoffError>;
+
+/// What the jump needs, checked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VectorTable {
+    /// Absolute address of the table, the value for VTOR.
+    pub addr: u32,
+    pub initial_sp: u32,
+    /// Reset handler with the Thumb bit set.
+    pub reset_handler: u32,
+}
+
+/// Read and check the vector table of the image in `slot`.
+///
+/// `flash_base` is the address flash offset zero is mapped at and `ram` the
+/// memory the initial stack pointer must lie in. The stack pointer may equal
+/// `ram.end`, as the stack grows down from it.
+pub fn read_vector_table(
+    flash: &dyn Flash,
+    flash_base: u32,
+    slot: Slot,
+    header: &ImageHeader,
+    ram: &Range<u32>,
+) -> Result<VectorTable> {
+    let offset = slot.addr + header.header_size as usize;
+    let addr = flash_base + offset as u32;
+    if header.load_addr != addr {
+        return Err(HandoffError::LoadAddress { expected: addr, found: header.load_addr });
+    }
+    if !addr.is_multiple_of(VECTOR_TABLE_ALIGN) {
+        return Err(HandoffError::Unaligned(addr));
+    }
+
+    let mut words = [0u8; 8];
+    flash.read(offset, &mut words)?;
+    let initial_sp = u32::from_le_bytes([words[0], words[1], words[2], words[3]]);
+    let reset_handler = u32::from_le_bytes([words[4], words[5], words[6], words[7]]);
+
+    if initial_sp <= ram.start || initial_sp > ram.end || !initial_sp.is_multiple_of(4) {
+        return Err(HandoffError::StackPointer(initial_sp));
+    }
+    // Past the two words just read, before the end of the payload.
+    let code = addr + 8..addr + header.image_size;
+    if reset_handler & 1 == 0 || !code.contains(&(reset_handler & !1)) {
+        return Err(HandoffError::ResetHandler(reset_handler));
+    }
+
+    Ok(VectorTable { addr, initial_sp, reset_handler })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::ExpectedDigest;
+    use crate::flash::MockFlash;
+    use crate::image::{ImageVersion, IMAGE_HEADER_SIZE};
+
+    const BASE: u32 = 0x0800_0000;
+    const SLOT: Slot = Slot::new(0x1000, 0x1000);
+    const PAYLOAD: u32 = BASE + 0x1000 + IMAGE_HEADER_SIZE as u32;
+
+    fn image(sp: u32, reset: u32) -> (MockFlash, ImageHeader) {
+        let mut f = MockFlash::new(0x2000, 0x400, 0x100);
+        let mut table = [0u8; 8];
+        table[..4].copy_from_slice(&sp.to_le_bytes());
+        table[4..].copy_from_slice(&reset.to_le_bytes());
+        f.write_region(SLOT.addr + IMAGE_HEADER_SIZE, &table).unwrap();
+        let header = ImageHeader::new(PAYLOAD, 0x400, ImageVersion::new(1, 0, 0, 0), ExpectedDigest::Crc32(0));
+        (f, header)
+    }
+
+    #[test]
+    fn valid_table_is_accepted() {
+        let (f, header) = image(0x2002_0000, PAYLOAD + 0x1C5);
+        assert_eq!(
+            read_vector_table(&f, BASE, SLOT, &header, &STM32F411_RAM),
+            Ok(VectorTable { addr: PAYLOAD, initial_sp: 0x2002_0000, reset_handler: PAYLOAD + 0x1C5 })
+        );
+    }
+
+    #[test]
+    fn malformed_tables_are_refused() {
+        let cases = [
+            // Erased slot.
+            (0xFFFF_FFFF, 0xFFFF_FFFF, HandoffError::StackPointer(0xFFFF_FFFF)),
+            (0x2000_0000, PAYLOAD + 0x1C5, HandoffError::StackPointer(0x2000_0000)),
+            (0x2002_0004, PAYLOAD + 0x1C5, HandoffError::StackPointer(0x2002_0004)),
+            (0x2001_0002, PAYLOAD + 0x1C5, HandoffError::StackPointer(0x2001_0002)),
+            // ARM state, into the vector table, past the image.
+            (0x2002_0000, PAYLOAD + 0x1C4, HandoffError::ResetHandler(PAYLOAD + 0x1C4)),
+            (0x2002_0000, PAYLOAD + 0x1, HandoffError::ResetHandler(PAYLOAD + 0x1)),
+            (0x2002_0000, PAYLOAD + 0x401, HandoffError::ResetHandler(PAYLOAD + 0x401)),
+        ];
+        for (sp, reset, err) in cases {
+            let (f, header) = image(sp, reset);
+            assert_eq!(read_vector_table(&f, BASE, SLOT, &header, &STM32F411_RAM), Err(err));
+        }
+    }
+
+    #[test]
+    fn image_must_be_linked_for_the_slot() {
+        let (f, header) = image(0x2002_0000, PAYLOAD + 0x1C5);
+        let other = ImageHeader { load_addr: 0x0800_4200, ..header };
+        assert_eq!(
+            read_vector_table(&f, BASE, SLOT, &other, &STM32F411_RAM),
+            Err(HandoffError::LoadAddress { expected: PAYLOAD, found: 0x0800_4200 })
+        );
+
+        // A header size that leaves the table off the VTOR alignment.
+        let odd = ImageHeader { header_size: 0x100, load_addr: PAYLOAD - 0x100, ..header };
+        assert_eq!(
+            read_vector_table(&f, BASE, SLOT, &odd, &STM32F411_RAM),
+            Err(HandoffError::Unaligned(PAYLOAD - 0x100))
+        );
+    }
+}
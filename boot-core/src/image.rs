@@ -0,0 +1,329 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Firmware image header definition and parsing.
+//!
+//! Every image stored in a flash slot starts with a fixed-size header that
+//! describes the payload following it. The bootloader reads and validates
+//! this header before it trusts anything else in the slot, so an erased,
+//! half-written or foreign slot is rejected instead of being executed.
+//!
+//! On-flash layout (all fields little-endian):
+//!
+//! ```text
+//! 0x00  magic           u32   IMAGE_MAGIC
+//! 0x04  header_version  u16   IMAGE_HEADER_VERSION
+//! 0x06  header_size     u16   bytes reserved for the header (payload offset)
+//! 0x08  load_addr       u32   absolute address the payload executes from
+//! 0x0C  image_size      u32   payload length in bytes
+//! 0x10  version         u8 major, u8 minor, u16 patch
+//! 0x14  build           u32
+//! 0x18  flags           u32
+//! 0x1C  digest_type     u8    see DigestType
+//! 0x1D  reserved        u8
+//! 0x1E  security_ver    u16   anti-rollback security version
+//! 0x20  digest          [u8; 64]  digest of the payload, zero padded
+//! 0x60  header_crc      u32   CRC32 of bytes 0x00..0x60
+//! 0x80  signature       [u8; 64]  image signature (secure boot only)
+//! ```
+//!
+//! The rest of the reserved header area is padding so that the payload (and
+//! therefore the application vector table) starts on an aligned boundary.
+//! The signature is not covered by `header_crc`; it authenticates the
+//! encoded header bytes and the payload (see `signature.rs`).
+
+use core::fmt;
+
+use crate::digest::{DigestType, ExpectedDigest, MAX_DIGEST_LEN};
+use crate::flash::{Flash, FlashError};
+
+/// Magic word identifying an M2 image header ("M2IM").
+pub const IMAGE_MAGIC: u32 = 0x4D49_324D;
+
+/// Header layout version understood by this bootloader.
+pub const IMAGE_HEADER_VERSION: u16 = 2;
+
+/// Bytes reserved at the start of a slot for the header. The payload starts
+/// right after it, which keeps the vector table suitably aligned for VTOR.
+pub const IMAGE_HEADER_SIZE: usize = 0x200;
+
+/// Number of header bytes that carry encoded fields (including `header_crc`).
+pub const IMAGE_HEADER_ENCODED_LEN: usize = 0x64;
+
+/// Size of the digest field in the header.
+pub const IMAGE_DIGEST_LEN: usize = MAX_DIGEST_LEN;
+
+/// Offset of the image signature inside the reserved header area.
+pub const IMAGE_SIGNATURE_OFFSET: usize = 0x80;
+
+/// Size of the image signature field.
+pub const IMAGE_SIGNATURE_LEN: usize = 64;
+
+/// Offset of the header CRC; every byte before it is covered by the CRC.
+const HEADER_CRC_OFFSET: usize = 0x60;
+
+/// Errors returned while reading or validating an image header.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImageError {
+    Flash(FlashError),
+    BadMagic(u32),
+    UnsupportedHeaderVersion(u16),
+    BadHeaderSize(u16),
+    HeaderCrcMismatch { expected: u32, found: u32 },
+    UnsupportedDigest(u8),
+    InvalidImageSize(u32),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Flash(e) => write!(f, "image: {}", e),
+            ImageError::BadMagic(m) => write!(f, "image: bad magic {:#010x}", m),
+            ImageError::UnsupportedHeaderVersion(v) => {
+                write!(f, "image: unsupported header version {}", v)
+            }
+            ImageError::BadHeaderSize(s) => write!(f, "image: bad header size {}", s),
+            ImageError::HeaderCrcMismatch { expected, found } => write!(
+                f,
+                "image: header crc mismatch expected={:#010x} found={:#010x}",
+                expected, found
+            ),
+            ImageError::UnsupportedDigest(t) => write!(f, "image: unsupported digest type {}", t),
+            ImageError::InvalidImageSize(s) => write!(f, "image: invalid image size {}", s),
+        }
+    }
+}
+
+impl From<FlashError> for ImageError {
+    fn from(e: FlashError) -> Self {
+        ImageError::Flash(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, ImageError>;
+
+/// Semantic version of a firmware image.
+///
+/// Ordering compares `major`, `minor`, `patch` and then `build`, so the
+/// derived `Ord` can be used directly to pick the newest image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ImageVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u16,
+    pub build: u32,
+}
+
+impl ImageVersion {
+    pub const fn new(major: u8, minor: u8, patch: u16, build: u32) -> Self {
+        Self { major, minor, patch, build }
+    }
+}
+
+impl fmt::Display for ImageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}+{}", self.major, self.minor, self.patch, self.build)
+    }
+}
+
+/// Parsed and validated image header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    /// Bytes reserved for the header; the payload starts at this offset.
+    pub header_size: u16,
+    /// Absolute address the payload is linked to execute from.
+    pub load_addr: u32,
+    /// Payload length in bytes.
+    pub image_size: u32,
+    /// Semantic version of the image.
+    pub version: ImageVersion,
+    /// Anti-rollback security version. Images below the device's security
+    /// counter are refused (see `rollback.rs`). Zero in headers written
+    /// before the field existed.
+    pub security_version: u16,
+    /// Image feature flags (reserved, currently unused).
+    pub flags: u32,
+    /// Expected digest of the payload.
+    pub digest: ExpectedDigest,
+}
+
+impl ImageHeader {
+    /// Build a header for a payload protected by `digest`.
+    pub fn new(load_addr: u32, image_size: u32, version: ImageVersion, digest: ExpectedDigest) -> Self {
+        ImageHeader {
+            header_size: IMAGE_HEADER_SIZE as u16,
+            load_addr,
+            image_size,
+            version,
+            security_version: 0,
+            flags: 0,
+            digest,
+        }
+    }
+
+    /// Build a header for a payload protected by a CRC32 digest.
+    pub fn with_crc32(load_addr: u32, image_size: u32, version: ImageVersion, crc: u32) -> Self {
+        Self::new(load_addr, image_size, version, ExpectedDigest::Crc32(crc))
+    }
+
+    /// Parse a header from its encoded bytes.
+    ///
+    /// Checks the magic, layout version, header size, header CRC and digest
+    /// type. Slot-dependent checks are done by [`ImageHeader::validate`].
+    pub fn parse(bytes: &[u8; IMAGE_HEADER_ENCODED_LEN]) -> Result<Self> {
+        let magic = le_u32(bytes, 0x00);
+        if magic != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic(magic));
+        }
+
+        let header_version = le_u16(bytes, 0x04);
+        if header_version != IMAGE_HEADER_VERSION {
+            return Err(ImageError::UnsupportedHeaderVersion(header_version));
+        }
+
+        let stored_crc = le_u32(bytes, HEADER_CRC_OFFSET);
+        let computed_crc = header_crc(&bytes[..HEADER_CRC_OFFSET]);
+        if stored_crc != computed_crc {
+            return Err(ImageError::HeaderCrcMismatch { expected: stored_crc, found: computed_crc });
+        }
+
+        let header_size = le_u16(bytes, 0x06);
+        if (header_size as usize) < IMAGE_SIGNATURE_OFFSET + IMAGE_SIGNATURE_LEN || !header_size.is_multiple_of(4) {
+            return Err(ImageError::BadHeaderSize(header_size));
+        }
+
+        let digest_type =
+            DigestType::from_u8(bytes[0x1C]).ok_or(ImageError::UnsupportedDigest(bytes[0x1C]))?;
+
+        let mut field = [0u8; IMAGE_DIGEST_LEN];
+        field.copy_from_slice(&bytes[0x20..0x20 + IMAGE_DIGEST_LEN]);
+
+        Ok(ImageHeader {
+            header_size,
+            load_addr: le_u32(bytes, 0x08),
+            image_size: le_u32(bytes, 0x0C),
+            version: ImageVersion {
+                major: bytes[0x10],
+                minor: bytes[0x11],
+                patch: le_u16(bytes, 0x12),
+                build: le_u32(bytes, 0x14),
+            },
+            security_version: le_u16(bytes, 0x1E),
+            flags: le_u32(bytes, 0x18),
+            digest: ExpectedDigest::from_field(digest_type, &field),
+        })
+    }
+
+    /// Encode the header, computing the header CRC.
+    pub fn to_bytes(&self) -> [u8; IMAGE_HEADER_ENCODED_LEN] {
+        let mut out = [0u8; IMAGE_HEADER_ENCODED_LEN];
+        out[0x00..0x04].copy_from_slice(&IMAGE_MAGIC.to_le_bytes());
+        out[0x04..0x06].copy_from_slice(&IMAGE_HEADER_VERSION.to_le_bytes());
+        out[0x06..0x08].copy_from_slice(&self.header_size.to_le_bytes());
+        out[0x08..0x0C].copy_from_slice(&self.load_addr.to_le_bytes());
+        out[0x0C..0x10].copy_from_slice(&self.image_size.to_le_bytes());
+        out[0x10] = self.version.major;
+        out[0x11] = self.version.minor;
+        out[0x12..0x14].copy_from_slice(&self.version.patch.to_le_bytes());
+        out[0x14..0x18].copy_from_slice(&self.version.build.to_le_bytes());
+        out[0x18..0x1C].copy_from_slice(&self.flags.to_le_bytes());
+        out[0x1C] = self.digest.digest_type() as u8;
+        out[0x1E..0x20].copy_from_slice(&self.security_version.to_le_bytes());
+        out[0x20..0x20 + IMAGE_DIGEST_LEN].copy_from_slice(&self.digest.to_field());
+        let crc = header_crc(&out[..HEADER_CRC_OFFSET]);
+        out[HEADER_CRC_OFFSET..HEADER_CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+        out
+    }
+
+    /// Check that the header describes an image that fits in a slot of
+    /// `slot_size` bytes.
+    pub fn validate(&self, slot_size: usize) -> Result<()> {
+        let total = (self.header_size as usize).checked_add(self.image_size as usize);
+        match total {
+            Some(t) if self.image_size != 0 && t <= slot_size => Ok(()),
+            _ => Err(ImageError::InvalidImageSize(self.image_size)),
+        }
+    }
+
+    /// Read, parse and validate the header at the start of a slot.
+    pub fn read_from(flash: &dyn Flash, slot_addr: usize, slot_size: usize) -> Result<Self> {
+        let mut buf = [0u8; IMAGE_HEADER_ENCODED_LEN];
+        flash.read(slot_addr, &mut buf)?;
+        let header = Self::parse(&buf)?;
+        header.validate(slot_size)?;
+        Ok(header)
+    }
+
+    /// Flash address of the payload for an image stored at `slot_addr`.
+    pub fn payload_addr(&self, slot_addr: usize) -> usize {
+        slot_addr + self.header_size as usize
+    }
+}
+
+fn header_crc(bytes: &[u8]) -> u32 {
+    let mut crc = crc_any::CRCu32::crc32();
+    crc.digest(bytes);
+    crc.get_crc()
+}
+
+fn le_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn le_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MockFlash;
+
+    fn sample_header() -> ImageHeader {
+        ImageHeader::with_crc32(0x0800_4200, 1024, ImageVersion::new(1, 2, 3, 4), 0x1234_5678)
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        let hdr = sample_header();
+        let parsed = ImageHeader::parse(&hdr.to_bytes()).unwrap();
+        assert_eq!(parsed, hdr);
+        assert_eq!(parsed.digest, ExpectedDigest::Crc32(0x1234_5678));
+
+        let sha512 = ImageHeader::new(0x0800_4200, 1024, ImageVersion::new(2, 0, 0, 0), ExpectedDigest::Sha512([0xA5; 64]));
+        assert_eq!(ImageHeader::parse(&sha512.to_bytes()).unwrap(), sha512);
+
+        let secured = ImageHeader { security_version: 0x0102, ..hdr };
+        assert_eq!(&secured.to_bytes()[0x1E..0x20], &[0x02, 0x01]);
+        assert_eq!(ImageHeader::parse(&secured.to_bytes()).unwrap(), secured);
+    }
+
+    #[test]
+    fn rejects_erased_and_corrupted_headers() {
+        let erased = [0xFFu8; IMAGE_HEADER_ENCODED_LEN];
+        assert_eq!(ImageHeader::parse(&erased), Err(ImageError::BadMagic(0xFFFF_FFFF)));
+
+        let mut bytes = sample_header().to_bytes();
+        bytes[0x0C] ^= 0x01;
+        assert!(matches!(ImageHeader::parse(&bytes), Err(ImageError::HeaderCrcMismatch { .. })));
+    }
+
+    #[test]
+    fn read_from_slot_checks_size() {
+        let mut f = MockFlash::new(4096, 1024, 256);
+        let hdr = sample_header();
+        f.write_region(1024, &hdr.to_bytes()).unwrap();
+
+        let read = ImageHeader::read_from(&f, 1024, 2048).unwrap();
+        assert_eq!(read.payload_addr(1024), 1024 + IMAGE_HEADER_SIZE);
+
+        // Header plus payload does not fit in a 1 KiB slot.
+        assert_eq!(ImageHeader::read_from(&f, 1024, 1024), Err(ImageError::InvalidImageSize(1024)));
+    }
+}
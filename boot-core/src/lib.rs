@@ -0,0 +1,40 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Hardware-independent bootloader core.
+//!
+//! Flash abstraction, image format, digests, signatures, the updater and the
+//! boot decision. The crate is `no_std`; the `std` feature (and `cargo test`)
+//! add `MockFlash` so the whole suite runs on the host. Board crates such as
+//! `bootloader` only wire these pieces to the hardware.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod boot;
+pub mod bootinfo;
+pub mod client;
+pub mod deinit;
+pub mod digest;
+pub mod flash;
+pub mod handoff;
+pub mod image;
+pub mod mailbox;
+#[cfg(feature = "overwrite-only")]
+pub mod overwrite;
+pub mod rollback;
+#[cfg(feature = "secure-boot")]
+pub mod signature;
+pub mod slot;
+pub mod stm32f4;
+#[cfg(not(feature = "overwrite-only"))]
+pub mod swap;
+pub mod trailer;
+pub mod updater;
+pub mod verify;
+pub mod watchdog;
@@ -0,0 +1,110 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Requests from the application to the bootloader.
+//!
+//! The application writes a `BootRequest` into a small no-init RAM mailbox
+//! (the `BOOT_REQUEST` region in both images' `memory.x`) and resets the MCU
+//! through `SYSRESETREQ`, which keeps SRAM. The bootloader takes the request
+//! right after `init_hardware` and clears the mailbox, so a request is acted
+//! on at most once.
+//!
+//! The mailbox holds a magic, the request code and its complement. SRAM
+//! comes up undefined on power-on, so leftover bytes are unlikely to read as
+//! a request, and the bootloader also ignores requests unless the reset was
+//! a software one.
+
+/// "BREQ", little-endian.
+pub const MAILBOX_MAGIC: u32 = 0x5145_5242;
+
+/// Encoded mailbox length.
+pub const MAILBOX_LEN: usize = 12;
+
+/// What the application asks the bootloader to do on the next reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootRequest {
+    /// Stay in the bootloader in update (recovery) mode. There is no update
+    /// link yet: the bootloader waits with the watchdog fed, then resets
+    /// into a normal boot.
+    Update,
+    /// Install the image in the secondary slot on trial even if it is not
+    /// newer, e.g. to go back to a previous version. Anti-rollback still
+    /// applies.
+    BootSecondary,
+}
+
+impl BootRequest {
+    const fn code(self) -> u32 {
+        match self {
+            BootRequest::Update => 0x5044_5055,
+            BootRequest::BootSecondary => 0x444E_4345,
+        }
+    }
+
+    fn from_code(code: u32) -> Option<Self> {
+        [BootRequest::Update, BootRequest::BootSecondary].into_iter().find(|r| r.code() == code)
+    }
+
+    /// Mailbox contents carrying this request.
+    pub fn encode(self) -> [u8; MAILBOX_LEN] {
+        let mut out = [0u8; MAILBOX_LEN];
+        out[0..4].copy_from_slice(&MAILBOX_MAGIC.to_le_bytes());
+        out[4..8].copy_from_slice(&self.code().to_le_bytes());
+        out[8..12].copy_from_slice(&(!self.code()).to_le_bytes());
+        out
+    }
+
+    /// Request held in `mailbox`, if any.
+    pub fn decode(mailbox: &[u8; MAILBOX_LEN]) -> Option<Self> {
+        let word = |i: usize| u32::from_le_bytes([mailbox[i], mailbox[i + 1], mailbox[i + 2], mailbox[i + 3]]);
+        if word(0) != MAILBOX_MAGIC || word(8) != !word(4) {
+            return None;
+        }
+        Self::from_code(word(4))
+    }
+}
+
+/// Take the request out of `mailbox`, leaving it empty.
+pub fn take(mailbox: &mut [u8; MAILBOX_LEN]) -> Option<BootRequest> {
+    let request = BootRequest::decode(mailbox);
+    mailbox.fill(0);
+    request
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_is_taken_once() {
+        for request in [BootRequest::Update, BootRequest::BootSecondary] {
+            let mut mailbox = request.encode();
+            assert_eq!(take(&mut mailbox), Some(request));
+            assert_eq!(take(&mut mailbox), None);
+        }
+    }
+
+    #[test]
+    fn garbage_is_no_request() {
+        assert_eq!(BootRequest::decode(&[0xFF; MAILBOX_LEN]), None);
+        assert_eq!(BootRequest::decode(&[0x00; MAILBOX_LEN]), None);
+
+        // Magic intact, code torn.
+        let mut mailbox = BootRequest::Update.encode();
+        mailbox[5] ^= 0x10;
+        assert_eq!(BootRequest::decode(&mailbox), None);
+
+        // Consistent, but not a request this bootloader knows.
+        let mut mailbox = BootRequest::Update.encode();
+        mailbox[4..8].copy_from_slice(&1u32.to_le_bytes());
+        mailbox[8..12].copy_from_slice(&(!1u32).to_le_bytes());
+        assert_eq!(take(&mut mailbox), None);
+        assert_eq!(mailbox, [0; MAILBOX_LEN]);
+    }
+}
@@ -0,0 +1,180 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Overwrite-only install (`overwrite-only` feature).
+//!
+//! For parts that cannot spare a scratch sector or the time of a swap. A
+//! verified secondary image is copied over the primary slot and the
+//! secondary slot is erased afterwards; the previous image is lost, so there
+//! is no going back to it.
+//!
+//! An install marker is written to the status area before the primary slot
+//! is erased and retired only after the new primary image verified. A reset
+//! in between leaves the marker behind, and the next boot restarts the copy
+//! from the beginning instead of booting a partial image. The secondary slot
+//! is not touched until the marker is retired, so the copy can always be
+//! redone.
+//!
+//! Status area layout (little-endian words):
+//!
+//! ```text
+//! 0x00  magic     OVERWRITE_MAGIC while an install runs, zeroed after
+//! ```
+
+use crate::flash::{Flash, FlashError, Result, READ_CHUNK_SIZE};
+use crate::slot::SlotLayout;
+use crate::trailer;
+
+#[cfg(feature = "swap-move")]
+compile_error!("features `swap-move` and `overwrite-only` are mutually exclusive");
+
+/// Marks an install in progress ("OWRT"). Differs from the swap magic, so a
+/// status area left by a swap build is never taken for an install.
+pub const OVERWRITE_MAGIC: u32 = 0x5452_574F;
+
+/// Status area bytes needed to record an install.
+pub const STATUS_LEN: usize = 4;
+
+/// Copy the first `len` bytes of the secondary slot over the primary slot,
+/// leaving the install marker set.
+///
+/// Call [`finish`] once the primary image verified. Every primary sector
+/// the image touches is erased, and so is the sector holding the primary
+/// trailer.
+pub fn install(flash: &mut dyn Flash, layout: &SlotLayout, len: usize) -> Result<()> {
+    if len == 0 || len > layout.primary.size || len > layout.secondary.size {
+        return Err(FlashError::OutOfBounds);
+    }
+    if layout.status.size < STATUS_LEN {
+        return Err(FlashError::OutOfBounds);
+    }
+
+    flash.erase_range(layout.status.addr, layout.status.size)?;
+    flash.program_region(layout.status.addr, &OVERWRITE_MAGIC.to_le_bytes())?;
+
+    // The primary trailer goes first: the state of the previous image does
+    // not apply to the new one, and the copy may erase its sector again.
+    trailer::erase(flash, layout, layout.primary)?;
+
+    // Copy in chunks that never cross a destination sector. The first chunk
+    // of each sector goes through `Flash::write_region`, which erases the
+    // sector; the rest of the sector is programmed behind it.
+    let (from, to) = (layout.secondary.addr, layout.primary.addr);
+    let mut buf = [0u8; READ_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let sector = flash.sector_at(to + offset).ok_or(FlashError::OutOfBounds)?;
+        let chunk = (len - offset).min(buf.len()).min(sector.end() - (to + offset));
+        flash.read(from + offset, &mut buf[..chunk])?;
+        if to + offset == sector.start {
+            flash.write_region(to + offset, &buf[..chunk])?;
+        } else {
+            flash.program_region(to + offset, &buf[..chunk])?;
+        }
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/// Retire the install marker, then erase the secondary slot so the image is
+/// not installed again.
+pub fn finish(flash: &mut dyn Flash, layout: &SlotLayout) -> Result<()> {
+    // Clear the marker first: a reset while erasing the secondary slot must
+    // not restart the copy from a half-erased image.
+    flash.program_region(layout.status.addr, &0u32.to_le_bytes())?;
+    flash.erase_range(layout.status.addr, layout.status.size)?;
+    flash.erase_range(layout.secondary.addr, layout.secondary.size)
+}
+
+/// Whether an install was cut short by a reset.
+pub fn in_progress(flash: &dyn Flash, layout: &SlotLayout) -> Result<bool> {
+    let mut buf = [0u8; 4];
+    flash.read(layout.status.addr, &mut buf)?;
+    Ok(u32::from_le_bytes(buf) == OVERWRITE_MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MockFlash;
+    use crate::slot::Slot;
+
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x100, 0x400),
+        Slot::new(0x500, 0x400),
+        Slot::new(0, 0),
+        Slot::new(0x000, 0x100),
+    );
+
+    #[test]
+    fn install_copies_and_finish_clears() {
+        let mut f = MockFlash::new(0x900, 0x100, 0x40);
+        f.write_region(LAYOUT.primary.addr, &[0x11u8; 0x400]).unwrap();
+        let staged: Vec<u8> = (0..0x300u32).map(|i| (i * 5) as u8).collect();
+        f.write_region(LAYOUT.secondary.addr, &staged).unwrap();
+
+        install(&mut f, &LAYOUT, staged.len()).unwrap();
+        assert!(in_progress(&f, &LAYOUT).unwrap());
+        assert_eq!(&f.storage[0x100..0x400], &staged[..]);
+
+        finish(&mut f, &LAYOUT).unwrap();
+        assert!(!in_progress(&f, &LAYOUT).unwrap());
+        assert!(f.storage[0x500..0x900].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn oversized_install_is_refused() {
+        let mut f = MockFlash::new(0x900, 0x100, 0x40);
+        assert_eq!(install(&mut f, &LAYOUT, 0x500), Err(FlashError::OutOfBounds));
+        assert!(!in_progress(&f, &LAYOUT).unwrap());
+    }
+
+    #[test]
+    fn power_loss_restarts_the_install() {
+        let old = [0x11u8; 0x400];
+        let staged: Vec<u8> = (0..0x2C0u32).map(|i| (i * 5) as u8).collect();
+        let fresh = || {
+            let mut f = MockFlash::new(0x900, 0x100, 0x40);
+            f.write_region(LAYOUT.primary.addr, &old).unwrap();
+            f.write_region(LAYOUT.secondary.addr, &staged).unwrap();
+            f.reboot();
+            f
+        };
+        let mut f = fresh();
+        install(&mut f, &LAYOUT, staged.len()).unwrap();
+        finish(&mut f, &LAYOUT).unwrap();
+        let steps = f.ops();
+
+        for cut in 0..steps {
+            let mut f = fresh();
+            f.seed_noise(cut as u32 + 1);
+            f.cut_power_after(cut);
+            let result = install(&mut f, &LAYOUT, staged.len()).and_then(|()| finish(&mut f, &LAYOUT));
+            assert_eq!(result, Err(MockFlash::POWER_LOSS));
+            f.reboot();
+
+            // What the bootloader does on the next boot: an install in
+            // progress is redone from the start, from the untouched
+            // secondary slot.
+            if in_progress(&f, &LAYOUT).unwrap() {
+                assert_eq!(&f.storage[0x500..0x500 + staged.len()], &staged[..]);
+                install(&mut f, &LAYOUT, staged.len()).unwrap();
+                finish(&mut f, &LAYOUT).unwrap();
+            }
+
+            // Otherwise power went before the primary slot was touched, or
+            // after the new image was complete.
+            let primary = &f.storage[0x100..0x100 + staged.len()];
+            assert!(primary == &old[..staged.len()] || primary == &staged[..], "partial image after cut {cut}");
+            if primary == &old[..staged.len()] {
+                assert_eq!(&f.storage[0x500..0x500 + staged.len()], &staged[..]);
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Anti-rollback security counter.
+//!
+//! Every image header carries a security version. The counter holds the
+//! lowest security version still accepted: the updater and the boot path
+//! refuse anything below it, so an old image with a known hole cannot come
+//! back even though its signature is valid. The counter only moves up, and
+//! only once an image carrying a higher security version was confirmed
+//! (see `boot.rs`), so a trial image that gets reverted never locks out the
+//! image it replaced.
+//!
+//! `SecurityCounter` is the storage interface; parts with OTP memory can
+//! implement it there. `FlashCounter` keeps the counter in a dedicated flash
+//! area as an append-only log of 8-byte entries (value, then its
+//! complement). Advancing programs one entry and never erases, so the area
+//! wears by one entry per security bump; a torn entry fails the complement
+//! check and is ignored, leaving the previous value in force.
+
+use crate::flash::{Flash, FlashError, Result};
+use crate::slot::{Slot, SlotError};
+
+const ENTRY_LEN: usize = 8;
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+/// Persistent, monotonic security counter.
+pub trait SecurityCounter {
+    /// Current counter value; zero when it was never advanced.
+    fn read(&self, flash: &dyn Flash) -> Result<u32>;
+
+    /// Raise the counter to `value`. Lower or equal values leave it as is.
+    ///
+    /// Storage that has run out of room reports `FlashError::OutOfBounds`;
+    /// the boot path then keeps the current value (see `boot.rs`).
+    fn advance(&self, flash: &mut dyn Flash, value: u32) -> Result<()>;
+}
+
+/// Security counter kept in a dedicated flash area.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashCounter {
+    area: Slot,
+}
+
+impl FlashCounter {
+    pub const fn new(area: Slot) -> Self {
+        Self { area }
+    }
+
+    /// Check the area lies on the device and covers whole sectors, so erasing
+    /// a neighbour can never take the counter with it.
+    pub fn check(&self, flash: &dyn Flash) -> core::result::Result<(), SlotError> {
+        if self.area.size < ENTRY_LEN || self.area.end() > flash.size() {
+            return Err(SlotError::OutOfBounds);
+        }
+        for boundary in [self.area.addr, self.area.end()] {
+            let aligned = boundary == flash.size() || flash.sector_at(boundary).is_some_and(|s| s.start == boundary);
+            if !aligned {
+                return Err(SlotError::Unaligned(boundary));
+            }
+        }
+        Ok(())
+    }
+
+    /// Value of the newest intact entry and the address of the first free one.
+    fn scan(&self, flash: &dyn Flash) -> Result<(u32, Option<usize>)> {
+        let mut value = 0;
+        for entry in 0..self.area.size / ENTRY_LEN {
+            let addr = self.area.addr + entry * ENTRY_LEN;
+            let mut buf = [0u8; ENTRY_LEN];
+            flash.read(addr, &mut buf)?;
+            let word = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let check = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+            if word == ERASED_WORD && check == ERASED_WORD {
+                return Ok((value, Some(addr)));
+            }
+            if check == !word {
+                value = value.max(word);
+            }
+        }
+        Ok((value, None))
+    }
+}
+
+impl SecurityCounter for FlashCounter {
+    fn read(&self, flash: &dyn Flash) -> Result<u32> {
+        self.scan(flash).map(|(value, _)| value)
+    }
+
+    /// Fails with `FlashError::OutOfBounds` once the area is full.
+    fn advance(&self, flash: &mut dyn Flash, value: u32) -> Result<()> {
+        let (current, free) = self.scan(flash)?;
+        if value <= current {
+            return Ok(());
+        }
+        let addr = free.ok_or(FlashError::OutOfBounds)?;
+        let mut entry = [0u8; ENTRY_LEN];
+        entry[..4].copy_from_slice(&value.to_le_bytes());
+        entry[4..].copy_from_slice(&(!value).to_le_bytes());
+        flash.program_region(addr, &entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MockFlash;
+
+    const COUNTER: FlashCounter = FlashCounter::new(Slot::new(0x100, 0x40));
+
+    #[test]
+    fn counter_only_moves_up() {
+        let mut f = MockFlash::new(0x200, 0x40, 0x20);
+        assert_eq!(COUNTER.read(&f), Ok(0));
+
+        COUNTER.advance(&mut f, 3).unwrap();
+        COUNTER.advance(&mut f, 2).unwrap();
+        COUNTER.advance(&mut f, 3).unwrap();
+        assert_eq!(COUNTER.read(&f), Ok(3));
+        // Only the first call wrote an entry.
+        assert!(f.storage[0x108..0x140].iter().all(|&b| b == 0xFF));
+
+        // A torn entry is ignored and the space after it still used.
+        f.program_page(0x108, &[9, 0, 0, 0]).unwrap();
+        assert_eq!(COUNTER.read(&f), Ok(3));
+        COUNTER.advance(&mut f, 5).unwrap();
+        assert_eq!(COUNTER.read(&f), Ok(5));
+        assert_eq!(f.erase_count(COUNTER.area.addr), 0);
+    }
+
+    #[test]
+    fn full_area_refuses_to_advance() {
+        let mut f = MockFlash::new(0x200, 0x40, 0x20);
+        for value in 1..=8 {
+            COUNTER.advance(&mut f, value).unwrap();
+        }
+        assert_eq!(COUNTER.advance(&mut f, 9), Err(FlashError::OutOfBounds));
+        assert_eq!(COUNTER.read(&f), Ok(8));
+    }
+
+    #[test]
+    fn torn_advance_keeps_previous_value() {
+        for seed in 1..=64 {
+            let mut f = MockFlash::new(0x200, 0x40, 0x20);
+            COUNTER.advance(&mut f, 3).unwrap();
+            f.seed_noise(seed);
+            f.cut_power_after(0);
+            assert_eq!(COUNTER.advance(&mut f, 0x1234_5678), Err(MockFlash::POWER_LOSS));
+            f.reboot();
+
+            // A torn entry reads as the old value or, if it happened to
+            // complete, the new one; never anything in between.
+            let value = COUNTER.read(&f).unwrap();
+            assert!(value == 3 || value == 0x1234_5678, "torn advance read as {value:#x}");
+            COUNTER.advance(&mut f, 0x1234_5678).unwrap();
+            assert_eq!(COUNTER.read(&f), Ok(0x1234_5678));
+        }
+    }
+
+    #[test]
+    fn area_must_cover_whole_sectors() {
+        let f = MockFlash::new(0x200, 0x40, 0x20);
+        assert_eq!(COUNTER.check(&f), Ok(()));
+        assert_eq!(FlashCounter::new(Slot::new(0x120, 0x40)).check(&f), Err(SlotError::Unaligned(0x120)));
+        assert_eq!(FlashCounter::new(Slot::new(0x1C0, 0x80)).check(&f), Err(SlotError::OutOfBounds));
+    }
+}
@@ -0,0 +1,348 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Image signature verification (secure boot).
+//!
+//! CRC and digest checks prove that an image is intact, not who built it.
+//! With the `secure-boot` feature enabled every image must carry a
+//! signature, stored in the reserved header area, over the SHA-256 hash of
+//! the encoded header followed by the payload. The bootloader checks it
+//! against a public key compiled into the bootloader before it boots an
+//! image or accepts an update.
+//!
+//! The algorithm is picked at build time with exactly one backend feature:
+//! - `ed25519`: Ed25519 signature over the 32-byte image hash.
+//! - `ecdsa-p256`: ECDSA P-256/SHA-256, the image hash is the prehashed
+//!   message. Signatures are stored as fixed-size `r || s`.
+//!
+//! Both backends use a 64-byte signature, so the image format and the call
+//! sites are the same whichever one is built in.
+
+use core::fmt;
+
+use crate::digest::{ImageDigest, Sha256};
+use crate::flash::{Flash, FlashError};
+use crate::image::{ImageHeader, IMAGE_HEADER_ENCODED_LEN, IMAGE_SIGNATURE_LEN, IMAGE_SIGNATURE_OFFSET};
+use crate::verify::digest_update_region;
+
+#[cfg(feature = "ed25519")]
+pub use self::ed25519::{PUBLIC_KEY_LEN, TRUSTED_PUBLIC_KEY};
+#[cfg(feature = "ecdsa-p256")]
+pub use self::ecdsa_p256::{PUBLIC_KEY_LEN, TRUSTED_PUBLIC_KEY};
+
+#[cfg(not(any(feature = "ed25519", feature = "ecdsa-p256")))]
+compile_error!("the `secure-boot` feature needs a signature backend, enable `ed25519` or `ecdsa-p256`");
+
+#[cfg(all(feature = "ed25519", feature = "ecdsa-p256"))]
+compile_error!("features `ed25519` and `ecdsa-p256` are mutually exclusive");
+
+/// Errors returned by signature verification.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SignatureError {
+    Flash(FlashError),
+    /// The stored signature or the public key could not be decoded.
+    Malformed,
+    /// The signature does not match the image.
+    Invalid,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Flash(e) => write!(f, "signature: {}", e),
+            SignatureError::Malformed => write!(f, "signature: malformed signature or key"),
+            SignatureError::Invalid => write!(f, "signature: verification failed"),
+        }
+    }
+}
+
+impl From<FlashError> for SignatureError {
+    fn from(e: FlashError) -> Self {
+        SignatureError::Flash(e)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, SignatureError>;
+
+/// Compute the SHA-256 hash that image signatures are made over: the encoded
+/// header bytes as stored in the slot followed by the payload.
+pub fn image_hash(flash: &dyn Flash, slot_addr: usize, header: &ImageHeader) -> core::result::Result<[u8; 32], FlashError> {
+    let mut hasher = Sha256::new();
+    digest_update_region(&mut hasher, flash, slot_addr, IMAGE_HEADER_ENCODED_LEN)?;
+    digest_update_region(&mut hasher, flash, header.payload_addr(slot_addr), header.image_size as usize)?;
+    Ok(hasher.finalize())
+}
+
+/// Read the signature stored in the header area of the slot at `slot_addr`.
+pub fn read_signature(flash: &dyn Flash, slot_addr: usize) -> core::result::Result<[u8; IMAGE_SIGNATURE_LEN], FlashError> {
+    let mut sig = [0u8; IMAGE_SIGNATURE_LEN];
+    flash.read(slot_addr + IMAGE_SIGNATURE_OFFSET, &mut sig)?;
+    Ok(sig)
+}
+
+/// Verify the image in the slot at `slot_addr` against the public key
+/// compiled into the bootloader.
+pub fn verify_image(flash: &dyn Flash, slot_addr: usize, header: &ImageHeader) -> Result<()> {
+    verify_image_with_key(flash, slot_addr, header, &TRUSTED_PUBLIC_KEY)
+}
+
+/// Verify the image in the slot at `slot_addr` against `public_key`.
+pub fn verify_image_with_key(
+    flash: &dyn Flash,
+    slot_addr: usize,
+    header: &ImageHeader,
+    public_key: &[u8; PUBLIC_KEY_LEN],
+) -> Result<()> {
+    let hash = image_hash(flash, slot_addr, header)?;
+    let sig = read_signature(flash, slot_addr)?;
+    backend::verify_hash(public_key, &hash, &sig)
+}
+
+#[cfg(feature = "ed25519")]
+use self::ed25519 as backend;
+
+#[cfg(feature = "ed25519")]
+mod ed25519 {
+    use super::{Result, SignatureError};
+    use crate::image::IMAGE_SIGNATURE_LEN;
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    /// Length of an Ed25519 public key.
+    pub const PUBLIC_KEY_LEN: usize = 32;
+
+    /// Ed25519 public key trusted by this bootloader.
+    ///
+    /// This is the development key (seed `[0x4D; 32]`). Replace it with the
+    /// product signing key before shipping.
+    pub const TRUSTED_PUBLIC_KEY: [u8; PUBLIC_KEY_LEN] = [
+        0x62, 0xa6, 0x11, 0xb4, 0x72, 0xd8, 0x9b, 0x0e,
+        0x5f, 0xc9, 0x3c, 0x06, 0x9b, 0x9f, 0x70, 0x0b,
+        0x4c, 0x55, 0x2d, 0x55, 0xbc, 0x0e, 0x87, 0xb5,
+        0x60, 0x08, 0xef, 0x17, 0xb6, 0xb2, 0xbe, 0xbe,
+    ];
+
+    pub fn verify_hash(
+        public_key: &[u8; PUBLIC_KEY_LEN],
+        hash: &[u8; 32],
+        sig: &[u8; IMAGE_SIGNATURE_LEN],
+    ) -> Result<()> {
+        let key = VerifyingKey::from_bytes(public_key).map_err(|_| SignatureError::Malformed)?;
+        let sig = Signature::from_bytes(sig);
+        key.verify_strict(hash, &sig).map_err(|_| SignatureError::Invalid)
+    }
+}
+
+#[cfg(feature = "ecdsa-p256")]
+use self::ecdsa_p256 as backend;
+
+#[cfg(feature = "ecdsa-p256")]
+mod ecdsa_p256 {
+    use super::{Result, SignatureError};
+    use crate::image::IMAGE_SIGNATURE_LEN;
+    use p256::ecdsa::signature::hazmat::PrehashVerifier;
+    use p256::ecdsa::{Signature, VerifyingKey};
+
+    /// Length of an uncompressed SEC1 P-256 public key.
+    pub const PUBLIC_KEY_LEN: usize = 65;
+
+    /// P-256 public key (uncompressed SEC1) trusted by this bootloader.
+    ///
+    /// This is the development key (scalar `[0x4D; 32]`). Replace it with
+    /// the product signing key before shipping.
+    pub const TRUSTED_PUBLIC_KEY: [u8; PUBLIC_KEY_LEN] = [
+        0x04, 0x66, 0x5f, 0xb8, 0xab, 0x47, 0xc8, 0xde,
+        0x4a, 0xbc, 0x6f, 0xa3, 0x1f, 0x88, 0x93, 0x7d,
+        0x99, 0x59, 0xf8, 0x6e, 0x33, 0x96, 0xa5, 0x79,
+        0xdb, 0xe1, 0xac, 0xa6, 0x0b, 0x06, 0xbd, 0x83,
+        0x36, 0xad, 0xc9, 0xcd, 0x1c, 0xee, 0x68, 0x31,
+        0xc7, 0x5c, 0x02, 0x69, 0x9c, 0xa8, 0x98, 0x60,
+        0xf7, 0xac, 0xac, 0x69, 0xff, 0xd0, 0x03, 0xc4,
+        0x96, 0xc8, 0x68, 0xe2, 0xba, 0xed, 0x49, 0x13,
+        0x0e,
+    ];
+
+    pub fn verify_hash(
+        public_key: &[u8; PUBLIC_KEY_LEN],
+        hash: &[u8; 32],
+        sig: &[u8; IMAGE_SIGNATURE_LEN],
+    ) -> Result<()> {
+        let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| SignatureError::Malformed)?;
+        let sig = Signature::from_slice(sig).map_err(|_| SignatureError::Malformed)?;
+        key.verify_prehash(hash, &sig).map_err(|_| SignatureError::Invalid)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn hex(s: &str) -> Vec<u8> {
+            (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+        }
+
+        /// RFC 6979, A.2.5: ECDSA P-256 with SHA-256.
+        fn rfc6979_key() -> [u8; PUBLIC_KEY_LEN] {
+            let mut key = [0u8; PUBLIC_KEY_LEN];
+            key[0] = 0x04;
+            key[1..33].copy_from_slice(&hex("60FED4BA255A9D31C961EB74C6356D68C049B8923B61FA6CE669622E60F29FB6"));
+            key[33..].copy_from_slice(&hex("7903FE1008B8BC99A41AE9E95628BC64F2F1B20C2D7E9F5177A3C294D4462299"));
+            key
+        }
+
+        fn sha256(msg: &[u8]) -> [u8; 32] {
+            use crate::digest::{ImageDigest, Sha256};
+            let mut d = Sha256::new();
+            d.update(msg);
+            d.finalize()
+        }
+
+        #[test]
+        fn known_answer_vectors() {
+            let key = rfc6979_key();
+            let vectors = [
+                (
+                    &b"sample"[..],
+                    "EFD48B2AACB6A8FD1140DD9CD45E81D69D2C877B56AAF991C34D0EA84EAF3716",
+                    "F7CB1C942D657C41D436C7A1B6E29F65F3E900DBB9AFF4064DC4AB2F843ACDA8",
+                ),
+                (
+                    &b"test"[..],
+                    "F1ABB023518351CD71D881567B1EA663ED3EFCF6C5132B354F28D3B0B7D38367",
+                    "019F4113742A2B14BD25926B49C649155F267E60D3814B4C0CC84250E46F0083",
+                ),
+            ];
+            for (msg, r, s) in vectors {
+                let mut sig = [0u8; IMAGE_SIGNATURE_LEN];
+                sig[..32].copy_from_slice(&hex(r));
+                sig[32..].copy_from_slice(&hex(s));
+                assert_eq!(verify_hash(&key, &sha256(msg), &sig), Ok(()));
+
+                // The same signature must not verify another message.
+                assert_eq!(verify_hash(&key, &sha256(b"other"), &sig), Err(SignatureError::Invalid));
+            }
+        }
+
+        #[test]
+        fn malformed_key_is_rejected() {
+            let mut key = rfc6979_key();
+            key[0] = 0x05;
+            let sig = [0x11u8; IMAGE_SIGNATURE_LEN];
+            assert_eq!(verify_hash(&key, &sha256(b"sample"), &sig), Err(SignatureError::Malformed));
+        }
+    }
+}
+
+/// Signing helpers for host tests, matching the backend that is built in.
+#[cfg(test)]
+pub(crate) mod test_keys {
+    use super::PUBLIC_KEY_LEN;
+    use crate::image::IMAGE_SIGNATURE_LEN;
+
+    /// Seed (Ed25519) or scalar (P-256) of the development key.
+    pub const DEV_SEED: [u8; 32] = [0x4D; 32];
+
+    #[cfg(feature = "ed25519")]
+    pub fn sign_hash(seed: &[u8; 32], hash: &[u8; 32]) -> [u8; IMAGE_SIGNATURE_LEN] {
+        use ed25519_dalek::{Signer, SigningKey};
+        SigningKey::from_bytes(seed).sign(hash).to_bytes()
+    }
+
+    #[cfg(feature = "ed25519")]
+    pub fn public_key(seed: &[u8; 32]) -> [u8; PUBLIC_KEY_LEN] {
+        ed25519_dalek::SigningKey::from_bytes(seed).verifying_key().to_bytes()
+    }
+
+    #[cfg(feature = "ecdsa-p256")]
+    pub fn sign_hash(seed: &[u8; 32], hash: &[u8; 32]) -> [u8; IMAGE_SIGNATURE_LEN] {
+        use p256::ecdsa::signature::hazmat::PrehashSigner;
+        use p256::ecdsa::{Signature, SigningKey};
+        let key = SigningKey::from_bytes(seed.into()).unwrap();
+        let sig: Signature = key.sign_prehash(hash).unwrap();
+        sig.to_bytes().into()
+    }
+
+    #[cfg(feature = "ecdsa-p256")]
+    pub fn public_key(seed: &[u8; 32]) -> [u8; PUBLIC_KEY_LEN] {
+        let key = p256::ecdsa::SigningKey::from_bytes(seed.into()).unwrap();
+        let mut out = [0u8; PUBLIC_KEY_LEN];
+        out.copy_from_slice(key.verifying_key().to_encoded_point(false).as_bytes());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::test_keys::{public_key, sign_hash, DEV_SEED};
+    use crate::flash::MockFlash;
+    use crate::image::{ImageVersion, IMAGE_HEADER_SIZE};
+
+    const SLOT: usize = 0x1000;
+
+    /// Write a signed image into a MockFlash slot and return its header.
+    fn signed_image(flash: &mut MockFlash, seed: &[u8; 32], payload: &[u8]) -> ImageHeader {
+        let crc = {
+            let mut c = crc_any::CRCu32::crc32();
+            c.digest(payload);
+            c.get_crc()
+        };
+        let header = ImageHeader::with_crc32(0x0800_4200, payload.len() as u32, ImageVersion::new(1, 0, 0, 0), crc);
+        let mut image = vec![0xFFu8; IMAGE_HEADER_SIZE + payload.len()];
+        image[..IMAGE_HEADER_ENCODED_LEN].copy_from_slice(&header.to_bytes());
+        image[IMAGE_HEADER_SIZE..].copy_from_slice(payload);
+        flash.write_region(SLOT, &image).unwrap();
+
+        let hash = image_hash(flash, SLOT, &header).unwrap();
+        flash.program_page(SLOT + IMAGE_SIGNATURE_OFFSET, &sign_hash(seed, &hash)).unwrap();
+        header
+    }
+
+    #[test]
+    fn development_key_matches_trusted_key() {
+        assert_eq!(public_key(&DEV_SEED), TRUSTED_PUBLIC_KEY);
+    }
+
+    #[test]
+    fn valid_signature_is_accepted() {
+        let mut f = MockFlash::new(8192, 1024, 256);
+        let header = signed_image(&mut f, &DEV_SEED, &[0x5Au8; 1500]);
+        assert_eq!(verify_image(&f, SLOT, &header), Ok(()));
+    }
+
+    #[test]
+    fn tampered_payload_is_rejected() {
+        let mut f = MockFlash::new(8192, 1024, 256);
+        let header = signed_image(&mut f, &DEV_SEED, &[0x5Au8; 1500]);
+
+        // Clear a single bit in the payload (programming 1 -> 0 is allowed).
+        f.program_page(SLOT + IMAGE_HEADER_SIZE + 700, &[0x58]).unwrap();
+        assert_eq!(verify_image(&f, SLOT, &header), Err(SignatureError::Invalid));
+    }
+
+    #[test]
+    fn flipped_bits_are_rejected() {
+        use crate::flash::FlipOn;
+
+        // In the signature itself, and in the header it covers.
+        for addr in [SLOT + IMAGE_SIGNATURE_OFFSET + 17, SLOT + 4] {
+            let mut f = MockFlash::new(8192, 1024, 256);
+            let header = signed_image(&mut f, &DEV_SEED, &[0x5Au8; 1500]);
+            f.flip_bit(addr, 6, FlipOn::Read);
+            assert_eq!(verify_image(&f, SLOT, &header), Err(SignatureError::Invalid));
+        }
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let mut f = MockFlash::new(8192, 1024, 256);
+        let other = [0x11u8; 32];
+        let header = signed_image(&mut f, &other, &[0x5Au8; 1500]);
+        assert_eq!(verify_image(&f, SLOT, &header), Err(SignatureError::Invalid));
+        assert_eq!(verify_image_with_key(&f, SLOT, &header, &public_key(&other)), Ok(()));
+    }
+}
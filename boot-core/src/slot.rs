@@ -0,0 +1,344 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Dual-slot (A/B) image layout.
+//!
+//! The primary slot holds the image that executes; the secondary slot is
+//! where updates are staged. Downloads only ever write the secondary slot,
+//! and an image is copied into the primary slot only after it verified in
+//! full, so a bad or interrupted download leaves the running image intact.
+//! Installing swaps the two slots through the scratch area, or with
+//! `swap-move` through a spare sector at the end of the primary slot, keeping
+//! its progress in the status area (`swap.rs`). With `overwrite-only` the
+//! secondary image is copied over the primary one instead (`overwrite.rs`).
+//! Which slot to boot is decided in `boot.rs`.
+
+use core::fmt;
+
+use crate::digest::ImageDigest;
+use crate::flash::Flash;
+use crate::image::ImageHeader;
+use crate::rollback::SecurityCounter;
+use crate::trailer::{self, TRAILER_LEN};
+#[cfg(not(feature = "overwrite-only"))]
+use crate::swap::status_len;
+use crate::updater::{FirmwareUpdater, UpdateError, UpdateMetadata, UpdateResult};
+
+/// A flash region holding one image (header followed by payload).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Slot {
+    /// Offset of the slot from the start of the flash device.
+    pub addr: usize,
+    pub size: usize,
+}
+
+impl Slot {
+    pub const fn new(addr: usize, size: usize) -> Self {
+        Self { addr, size }
+    }
+
+    /// First address past the slot.
+    pub const fn end(&self) -> usize {
+        self.addr + self.size
+    }
+}
+
+/// Errors found while checking a slot layout against a flash device.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SlotError {
+    /// A slot boundary does not fall on a sector boundary.
+    Unaligned(usize),
+    OutOfBounds,
+    Overlap,
+    /// Primary and secondary slots do not have the same sector layout (with
+    /// `swap-move`: not uniform, or no spare sector in the primary slot;
+    /// with `overwrite-only`: secondary larger than primary).
+    GeometryMismatch,
+    /// The scratch area cannot hold the largest slot sector.
+    ScratchTooSmall,
+    /// The status area cannot record a swap of the whole slot.
+    StatusTooSmall,
+}
+
+impl fmt::Display for SlotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SlotError::Unaligned(addr) => write!(f, "slot: boundary {:#010x} not sector aligned", addr),
+            SlotError::OutOfBounds => write!(f, "slot: outside the flash device"),
+            SlotError::Overlap => write!(f, "slot: areas overlap"),
+            SlotError::GeometryMismatch => write!(f, "slot: primary and secondary sector layouts differ"),
+            SlotError::ScratchTooSmall => write!(f, "slot: scratch smaller than a slot sector"),
+            SlotError::StatusTooSmall => write!(f, "slot: status area too small"),
+        }
+    }
+}
+
+/// Primary (execute) and secondary (staging) slots, plus the scratch and
+/// status areas used to swap them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotLayout {
+    pub primary: Slot,
+    pub secondary: Slot,
+    pub scratch: Slot,
+    pub status: Slot,
+}
+
+impl SlotLayout {
+    pub const fn new(primary: Slot, secondary: Slot, scratch: Slot, status: Slot) -> Self {
+        Self { primary, secondary, scratch, status }
+    }
+
+    /// Largest image either slot can hold: the swapped area (the size of the
+    /// secondary slot) less the image state trailer at its end.
+    pub const fn image_capacity(&self) -> usize {
+        self.secondary.size - TRAILER_LEN
+    }
+
+    /// Check that every area lies on the device, starts and ends on sector
+    /// boundaries and does not overlap another, and that the slots can be
+    /// swapped sector by sector.
+    pub fn check(&self, flash: &dyn Flash) -> Result<(), SlotError> {
+        // Only swap-scratch uses the scratch area.
+        #[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+        let areas = [self.primary, self.secondary, self.scratch, self.status];
+        #[cfg(any(feature = "swap-move", feature = "overwrite-only"))]
+        let areas = [self.primary, self.secondary, self.status];
+        for slot in areas {
+            if slot.size == 0 || slot.end() > flash.size() {
+                return Err(SlotError::OutOfBounds);
+            }
+            for boundary in [slot.addr, slot.end()] {
+                let aligned = boundary == flash.size()
+                    || flash.sector_at(boundary).is_some_and(|s| s.start == boundary);
+                if !aligned {
+                    return Err(SlotError::Unaligned(boundary));
+                }
+            }
+        }
+        for (i, a) in areas.iter().enumerate() {
+            if areas[i + 1..].iter().any(|b| a.addr < b.end() && b.addr < a.end()) {
+                return Err(SlotError::Overlap);
+            }
+        }
+        if self.secondary.size <= TRAILER_LEN {
+            return Err(SlotError::OutOfBounds);
+        }
+
+        self.check_install(flash)
+    }
+
+    /// Check a full-slot swap is possible: the two slots match sector for
+    /// sector, each fits the scratch area and the status area can record it.
+    #[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+    fn check_install(&self, flash: &dyn Flash) -> Result<(), SlotError> {
+        if self.primary.size != self.secondary.size {
+            return Err(SlotError::GeometryMismatch);
+        }
+        let mut offset = 0;
+        let mut sectors = 0;
+        while offset < self.primary.size {
+            let p = flash.sector_at(self.primary.addr + offset).ok_or(SlotError::OutOfBounds)?;
+            let s = flash.sector_at(self.secondary.addr + offset).ok_or(SlotError::OutOfBounds)?;
+            if s.start != self.secondary.addr + offset || s.size != p.size {
+                return Err(SlotError::GeometryMismatch);
+            }
+            if p.size > self.scratch.size {
+                return Err(SlotError::ScratchTooSmall);
+            }
+            offset += p.size;
+            sectors += 1;
+        }
+        if status_len(sectors) > self.status.size {
+            return Err(SlotError::StatusTooSmall);
+        }
+        Ok(())
+    }
+
+    /// Check a full-slot swap-move is possible: both slots are made of
+    /// same-sized sectors, the primary slot has the spare one and the status
+    /// area can record it.
+    #[cfg(feature = "swap-move")]
+    fn check_install(&self, flash: &dyn Flash) -> Result<(), SlotError> {
+        let size = crate::swap::move_sector_len(flash, self).map_err(|_| SlotError::GeometryMismatch)?;
+        if status_len(self.secondary.size / size) > self.status.size {
+            return Err(SlotError::StatusTooSmall);
+        }
+        Ok(())
+    }
+
+    /// Check any secondary image fits the primary slot and the status area
+    /// can hold the install marker.
+    #[cfg(feature = "overwrite-only")]
+    fn check_install(&self, _flash: &dyn Flash) -> Result<(), SlotError> {
+        if self.secondary.size > self.primary.size {
+            return Err(SlotError::GeometryMismatch);
+        }
+        if crate::overwrite::STATUS_LEN > self.status.size {
+            return Err(SlotError::StatusTooSmall);
+        }
+        Ok(())
+    }
+
+    /// Start staging the image described by `header` in the secondary slot.
+    ///
+    /// The primary slot is never touched; the image is promoted by the boot
+    /// logic once it verifies. The secondary trailer is erased, so the state
+    /// of the image previously in the slot does not carry over. An image
+    /// whose security version is below `counter` is refused before anything
+    /// is erased.
+    pub fn begin_update<'a, D: ImageDigest>(
+        &self,
+        flash: &'a mut dyn Flash,
+        header: &ImageHeader,
+        counter: &dyn SecurityCounter,
+    ) -> UpdateResult<FirmwareUpdater<'a, D>> {
+        header.validate(self.image_capacity()).map_err(UpdateError::Image)?;
+        if u32::from(header.security_version) < counter.read(flash)? {
+            return Err(UpdateError::Rollback(header.security_version));
+        }
+        trailer::erase(flash, self, self.secondary)?;
+        FirmwareUpdater::begin_update(flash, UpdateMetadata::from_header(header, self.secondary.addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::{ExpectedDigest, Sha256};
+    use crate::flash::{MockFlash, SectorRegion};
+    use crate::image::{ImageVersion, IMAGE_HEADER_ENCODED_LEN, IMAGE_HEADER_SIZE};
+    use crate::rollback::FlashCounter;
+    use crate::trailer::ImageState;
+
+    const COUNTER: FlashCounter = FlashCounter::new(Slot::new(0x0400, 0x400));
+
+    // status | primary | secondary | scratch
+    #[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x1000, 0x1000),
+        Slot::new(0x2000, 0x1000),
+        Slot::new(0x3000, 0x400),
+        Slot::new(0x0000, 0x400),
+    );
+
+    // status | primary (+1 spare sector) | secondary
+    #[cfg(feature = "swap-move")]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x1000, 0x1400),
+        Slot::new(0x2400, 0x1000),
+        Slot::new(0, 0),
+        Slot::new(0x0000, 0x400),
+    );
+
+    #[test]
+    fn layout_checks() {
+        let f = MockFlash::new(0x4000, 0x400, 256);
+        assert_eq!(LAYOUT.check(&f), Ok(()));
+
+        let unaligned = SlotLayout { primary: Slot::new(0x900, 0x1000), ..LAYOUT };
+        assert_eq!(unaligned.check(&f), Err(SlotError::Unaligned(0x900)));
+        let overlap = SlotLayout { status: Slot::new(0x1000, 0x400), ..LAYOUT };
+        assert_eq!(overlap.check(&f), Err(SlotError::Overlap));
+        let outside = SlotLayout { secondary: Slot::new(0x3400, 0x2000), ..LAYOUT };
+        assert_eq!(outside.check(&f), Err(SlotError::OutOfBounds));
+    }
+
+    // status | primary | secondary
+    #[cfg(feature = "overwrite-only")]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x1000, 0x1000),
+        Slot::new(0x2000, 0x1000),
+        Slot::new(0, 0),
+        Slot::new(0x0000, 0x400),
+    );
+
+    #[test]
+    #[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+    fn layout_checks_swap_geometry() {
+        // Slots made of one 4 KiB sector each do not fit a 1 KiB scratch.
+        let big = [SectorRegion::new(4, 0x400), SectorRegion::new(2, 0x1000), SectorRegion::new(4, 0x400)];
+        assert_eq!(LAYOUT.check(&MockFlash::with_sectors(&big, 256)), Err(SlotError::ScratchTooSmall));
+
+        // Primary is one 4 KiB sector, secondary four 1 KiB sectors.
+        let mixed = [SectorRegion::new(4, 0x400), SectorRegion::new(1, 0x1000), SectorRegion::new(8, 0x400)];
+        assert_eq!(LAYOUT.check(&MockFlash::with_sectors(&mixed, 256)), Err(SlotError::GeometryMismatch));
+
+        let tiny_status = SlotLayout { status: Slot::new(0x0, 0x20), ..LAYOUT };
+        let small = MockFlash::new(0x4000, 0x20, 0x20);
+        assert_eq!(tiny_status.check(&small), Err(SlotError::StatusTooSmall));
+    }
+
+    #[test]
+    #[cfg(feature = "swap-move")]
+    fn layout_checks_move_geometry() {
+        // Primary slot without the spare sector.
+        let no_spare = SlotLayout { primary: Slot::new(0x1000, 0x1000), ..LAYOUT };
+        assert_eq!(no_spare.check(&MockFlash::new(0x4000, 0x400, 256)), Err(SlotError::GeometryMismatch));
+
+        // One 4 KiB sector inside the primary slot.
+        let mixed = [SectorRegion::new(4, 0x400), SectorRegion::new(1, 0x1000), SectorRegion::new(8, 0x400)];
+        assert_eq!(LAYOUT.check(&MockFlash::with_sectors(&mixed, 256)), Err(SlotError::GeometryMismatch));
+
+        let tiny_status = SlotLayout { status: Slot::new(0x0, 0x20), ..LAYOUT };
+        let small = MockFlash::new(0x4000, 0x20, 0x20);
+        assert_eq!(tiny_status.check(&small), Err(SlotError::StatusTooSmall));
+    }
+
+    #[test]
+    #[cfg(feature = "overwrite-only")]
+    fn layout_checks_overwrite_fit() {
+        // Sector layouts need not match, the copy erases by the primary's.
+        let mixed = [SectorRegion::new(4, 0x400), SectorRegion::new(1, 0x1000), SectorRegion::new(8, 0x400)];
+        assert_eq!(LAYOUT.check(&MockFlash::with_sectors(&mixed, 256)), Ok(()));
+
+        let big_secondary = SlotLayout { secondary: Slot::new(0x2000, 0x1400), ..LAYOUT };
+        assert_eq!(big_secondary.check(&MockFlash::new(0x4000, 0x400, 256)), Err(SlotError::GeometryMismatch));
+    }
+
+    #[test]
+    fn update_is_staged_in_secondary() {
+        let mut f = MockFlash::new(0x3400, 0x400, 256);
+        f.write_region(LAYOUT.primary.addr, &vec![0x11u8; LAYOUT.primary.size]).unwrap();
+
+        let header = ImageHeader::new(0x0800_1200, 0x900, ImageVersion::new(2, 0, 0, 0), ExpectedDigest::Sha256([0; 32]));
+        let mut image = vec![0xFFu8; IMAGE_HEADER_SIZE];
+        image[..IMAGE_HEADER_ENCODED_LEN].copy_from_slice(&header.to_bytes());
+        trailer::write_state(&mut f, &LAYOUT, LAYOUT.secondary, ImageState::Reverted).unwrap();
+        {
+            let mut updater = LAYOUT.begin_update::<Sha256>(&mut f, &header, &COUNTER).unwrap();
+            updater.write_chunk(0, &image).unwrap();
+        }
+
+        assert_eq!(ImageHeader::read_from(&f, LAYOUT.secondary.addr, LAYOUT.secondary.size), Ok(header));
+        // The state of the image staged before is gone.
+        assert_eq!(trailer::read_state(&f, &LAYOUT, LAYOUT.secondary), Ok(None));
+        assert!(f.storage[LAYOUT.primary.addr..LAYOUT.primary.end()].iter().all(|&b| b == 0x11));
+
+        // An image too large for the secondary slot is refused up front.
+        let big = ImageHeader { image_size: 0x1000, ..header };
+        assert!(matches!(LAYOUT.begin_update::<Sha256>(&mut f, &big, &COUNTER), Err(UpdateError::Image(_))));
+    }
+
+    #[test]
+    fn update_below_security_counter_is_refused() {
+        let mut f = MockFlash::new(0x3400, 0x400, 256);
+        COUNTER.advance(&mut f, 2).unwrap();
+        let mut header =
+            ImageHeader::new(0x0800_1200, 0x900, ImageVersion::new(3, 0, 0, 0), ExpectedDigest::Sha256([0; 32]));
+        header.security_version = 1;
+        f.write_region(LAYOUT.secondary.addr, &[0x22u8; 0x400]).unwrap();
+
+        assert!(matches!(LAYOUT.begin_update::<Sha256>(&mut f, &header, &COUNTER), Err(UpdateError::Rollback(1))));
+        // Nothing was erased.
+        assert!(f.storage[LAYOUT.secondary.addr..LAYOUT.secondary.addr + 0x400].iter().all(|&b| b == 0x22));
+
+        header.security_version = 2;
+        assert!(LAYOUT.begin_update::<Sha256>(&mut f, &header, &COUNTER).is_ok());
+    }
+}
@@ -0,0 +1,560 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! STM32F4 embedded flash controller driver.
+//!
+//! This module provides:
+//! - `InternalFlash`, a [`Flash`] implementation driving FLASH_KEYR/SR/CR
+//!   (unlock sequence, sector-number erase, PSIZE-aware programming, busy
+//!   waiting with a timeout and SR error decoding).
+//! - The `Mmio` trait all register and memory accesses go through, with
+//!   `VolatileMmio` for the target.
+//! - `MockFlashController`, a register-level model of the peripheral so the
+//!   driver runs on the host (tests or the `std` feature).
+//!
+//! F4 sectors are not uniform (16/16/16/16/64/128/... KiB); the driver is
+//! given the part's sector map and derives SNB from the sector index.
+
+use crate::flash::{map_size, Flash, FlashError, Result, SectorRegion};
+
+#[cfg(any(test, feature = "std"))]
+use crate::flash::sectors;
+#[cfg(any(test, feature = "std"))]
+use core::cell::Cell;
+#[cfg(any(test, feature = "std"))]
+use std::{vec, vec::Vec};
+
+/// Start of the main flash memory in the address map.
+pub const FLASH_MEMORY_BASE: usize = 0x0800_0000;
+/// Base address of the FLASH interface registers.
+pub const FLASH_REGS_BASE: usize = 0x4002_3C00;
+
+const ACR: usize = FLASH_REGS_BASE;
+const KEYR: usize = FLASH_REGS_BASE + 0x04;
+const SR: usize = FLASH_REGS_BASE + 0x0C;
+const CR: usize = FLASH_REGS_BASE + 0x10;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const ACR_DCEN: u32 = 1 << 10;
+const ACR_DCRST: u32 = 1 << 12;
+
+const SR_EOP: u32 = 1 << 0;
+const SR_OPERR: u32 = 1 << 1;
+const SR_WRPERR: u32 = 1 << 4;
+const SR_PGAERR: u32 = 1 << 5;
+const SR_PGPERR: u32 = 1 << 6;
+const SR_PGSERR: u32 = 1 << 7;
+const SR_BSY: u32 = 1 << 16;
+const SR_ERRORS: u32 = SR_OPERR | SR_WRPERR | SR_PGAERR | SR_PGPERR | SR_PGSERR;
+
+const CR_PG: u32 = 1 << 0;
+const CR_SER: u32 = 1 << 1;
+const CR_SNB_SHIFT: u32 = 3;
+const CR_SNB_MASK: u32 = 0xF << CR_SNB_SHIFT;
+const CR_PSIZE_SHIFT: u32 = 8;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+
+/// Highest sector count addressable through the 4-bit SNB field.
+const MAX_SECTORS: usize = 12;
+
+/// Sector map of the STM32F411xE (512 KiB).
+pub const STM32F411_SECTORS: [SectorRegion; 3] = [
+    SectorRegion::new(4, 16 * 1024),
+    SectorRegion::new(1, 64 * 1024),
+    SectorRegion::new(3, 128 * 1024),
+];
+
+/// Default number of FLASH_SR polls before an operation is declared hung.
+/// Sized for a worst-case 128 KiB sector erase (2 s) at 100 MHz.
+pub const DEFAULT_TIMEOUT_POLLS: u32 = 0x0400_0000;
+
+/// Raw memory-mapped access used by the driver. Register accesses are
+/// always 32-bit; programming uses the width selected by `ProgramSize`.
+pub trait Mmio {
+    fn read8(&self, addr: usize) -> u8;
+    fn read32(&self, addr: usize) -> u32;
+    fn write8(&mut self, addr: usize, val: u8);
+    fn write16(&mut self, addr: usize, val: u16);
+    fn write32(&mut self, addr: usize, val: u32);
+}
+
+/// Volatile pointer accesses to the real address space.
+pub struct VolatileMmio;
+
+impl Mmio for VolatileMmio {
+    fn read8(&self, addr: usize) -> u8 {
+        unsafe { core::ptr::read_volatile(addr as *const u8) }
+    }
+    fn read32(&self, addr: usize) -> u32 {
+        unsafe { core::ptr::read_volatile(addr as *const u32) }
+    }
+    fn write8(&mut self, addr: usize, val: u8) {
+        unsafe { core::ptr::write_volatile(addr as *mut u8, val) }
+    }
+    fn write16(&mut self, addr: usize, val: u16) {
+        unsafe { core::ptr::write_volatile(addr as *mut u16, val) }
+    }
+    fn write32(&mut self, addr: usize, val: u32) {
+        unsafe { core::ptr::write_volatile(addr as *mut u32, val) }
+    }
+}
+
+/// Program parallelism (FLASH_CR.PSIZE). Must match the supply voltage
+/// range: x8 down to 1.7 V, x16 from 2.1 V, x32 from 2.7 V. x64 needs an
+/// external VPP and is not supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramSize {
+    X8 = 0,
+    X16 = 1,
+    X32 = 2,
+}
+
+impl ProgramSize {
+    /// Bytes written per program operation.
+    pub const fn bytes(self) -> usize {
+        1 << self as usize
+    }
+}
+
+/// Driver for the STM32F4 on-chip flash.
+///
+/// Offsets passed through the [`Flash`] trait are relative to `base_addr`.
+pub struct InternalFlash<M: Mmio = VolatileMmio> {
+    mmio: M,
+    pub base_addr: usize,
+    pub total_size: usize,
+    pub sectors: &'static [SectorRegion],
+    pub page_size: usize,
+    pub psize: ProgramSize,
+    pub timeout_polls: u32,
+}
+
+impl<M: Mmio> InternalFlash<M> {
+    pub const fn new(mmio: M, base_addr: usize, sectors: &'static [SectorRegion], page_size: usize, psize: ProgramSize) -> Self {
+        let total_size = map_size(sectors);
+        Self { mmio, base_addr, total_size, sectors, page_size, psize, timeout_polls: DEFAULT_TIMEOUT_POLLS }
+    }
+
+    /// Override the busy-wait budget (number of FLASH_SR polls).
+    pub const fn with_timeout(mut self, polls: u32) -> Self {
+        self.timeout_polls = polls;
+        self
+    }
+
+    pub fn mmio(&self) -> &M {
+        &self.mmio
+    }
+
+    fn wait_ready(&self) -> Result<()> {
+        let mut polls = self.timeout_polls;
+        while self.mmio.read32(SR) & SR_BSY != 0 {
+            if polls == 0 {
+                return Err(FlashError::Timeout);
+            }
+            polls -= 1;
+        }
+        Ok(())
+    }
+
+    /// Decode and clear (write-1-to-clear) the SR error flags.
+    fn take_errors(&mut self) -> Result<()> {
+        let sr = self.mmio.read32(SR);
+        self.mmio.write32(SR, sr & (SR_ERRORS | SR_EOP));
+        if sr & SR_WRPERR != 0 {
+            Err(FlashError::WriteProtected)
+        } else if sr & SR_PGAERR != 0 {
+            Err(FlashError::ProgramAlignment)
+        } else if sr & SR_PGPERR != 0 {
+            Err(FlashError::ProgramParallelism)
+        } else if sr & SR_PGSERR != 0 {
+            Err(FlashError::ProgramSequence)
+        } else if sr & SR_OPERR != 0 {
+            Err(FlashError::DeviceError("flash operation error"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Run `op` with FLASH_CR unlocked, relocking afterwards whatever the
+    /// outcome.
+    fn unlocked<F>(&mut self, op: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        self.wait_ready()?;
+        // Flags left over from an earlier failure would be reported
+        // against this operation.
+        self.mmio.write32(SR, SR_ERRORS | SR_EOP);
+        if self.mmio.read32(CR) & CR_LOCK != 0 {
+            self.mmio.write32(KEYR, KEY1);
+            self.mmio.write32(KEYR, KEY2);
+            if self.mmio.read32(CR) & CR_LOCK != 0 {
+                return Err(FlashError::Locked);
+            }
+        }
+        let result = op(self);
+        self.mmio.write32(CR, CR_LOCK);
+        result
+    }
+
+    /// Reset the data cache so reads after an erase do not return stale
+    /// lines (RM0383 3.4.2).
+    fn flush_data_cache(&mut self) {
+        let acr = self.mmio.read32(ACR);
+        if acr & ACR_DCEN != 0 {
+            self.mmio.write32(ACR, acr & !ACR_DCEN);
+            self.mmio.write32(ACR, (acr & !ACR_DCEN) | ACR_DCRST);
+            self.mmio.write32(ACR, acr & !ACR_DCRST);
+        }
+    }
+
+    fn program_unit(&mut self, abs: usize, unit: &[u8]) -> Result<()> {
+        match self.psize {
+            ProgramSize::X8 => self.mmio.write8(abs, unit[0]),
+            ProgramSize::X16 => self.mmio.write16(abs, u16::from_le_bytes([unit[0], unit[1]])),
+            ProgramSize::X32 => self.mmio.write32(abs, u32::from_le_bytes([unit[0], unit[1], unit[2], unit[3]])),
+        }
+        self.wait_ready()?;
+        self.take_errors()
+    }
+}
+
+impl<M: Mmio> Flash for InternalFlash<M> {
+    fn size(&self) -> usize { self.total_size }
+    fn sector_map(&self) -> &[SectorRegion] { self.sectors }
+    fn page_size(&self) -> usize { self.page_size }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> Result<()> {
+        if addr.checked_add(buf.len()).is_none() || addr + buf.len() > self.total_size {
+            return Err(FlashError::OutOfBounds);
+        }
+        let absolute = self.base_addr + addr;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.mmio.read8(absolute + i);
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, addr: usize) -> Result<()> {
+        let sector = self.sector_at(addr).ok_or(FlashError::OutOfBounds)?;
+        if sector.start != addr { return Err(FlashError::AlignmentError); }
+        if sector.index >= MAX_SECTORS { return Err(FlashError::DeviceError("sector number out of SNB range")); }
+
+        let psize = (self.psize as u32) << CR_PSIZE_SHIFT;
+        let snb = ((sector.index as u32) << CR_SNB_SHIFT) & CR_SNB_MASK;
+        self.unlocked(|f| {
+            f.mmio.write32(CR, CR_SER | snb | psize);
+            f.mmio.write32(CR, CR_SER | snb | psize | CR_STRT);
+            let result = f.wait_ready().and_then(|_| f.take_errors());
+            f.mmio.write32(CR, 0);
+            result
+        })?;
+        self.flush_data_cache();
+        Ok(())
+    }
+
+    /// Program `data` at `addr`. A head or tail that does not fill a whole
+    /// PSIZE unit is padded with 0xFF, which leaves those bits untouched.
+    fn program_page(&mut self, addr: usize, data: &[u8]) -> Result<()> {
+        if addr.checked_add(data.len()).is_none() || addr + data.len() > self.total_size {
+            return Err(FlashError::OutOfBounds);
+        }
+        if data.len() > self.page_size { return Err(FlashError::AlignmentError); }
+        if data.is_empty() { return Ok(()); }
+
+        let unit = self.psize.bytes();
+        let first = addr - addr % unit;
+        let end = addr + data.len();
+        let psize = (self.psize as u32) << CR_PSIZE_SHIFT;
+        let base = self.base_addr;
+        self.unlocked(|f| {
+            f.mmio.write32(CR, CR_PG | psize);
+            let mut result = Ok(());
+            let mut pos = first;
+            while pos < end {
+                let mut buf = [0xFFu8; 4];
+                for (i, b) in buf[..unit].iter_mut().enumerate() {
+                    if (addr..end).contains(&(pos + i)) {
+                        *b = data[pos + i - addr];
+                    }
+                }
+                result = f.program_unit(base + pos, &buf[..unit]);
+                if result.is_err() {
+                    break;
+                }
+                pos += unit;
+            }
+            f.mmio.write32(CR, 0);
+            result
+        })
+    }
+}
+
+// -----------------------------------------------------------------------------
+// MockFlashController - register-level model of the peripheral
+// -----------------------------------------------------------------------------
+
+/// Host model of the F4 flash interface and its memory array.
+///
+/// Implements the KEYR unlock sequence, CR locking, sector erase, PG
+/// programming with AND semantics and the PGSERR/PGPERR/PGAERR/WRPERR
+/// checks, so driver bugs show up as the same SR flags the silicon raises.
+#[cfg(any(test, feature = "std"))]
+pub struct MockFlashController {
+    pub memory: Vec<u8>,
+    sectors: &'static [SectorRegion],
+    acr: u32,
+    sr: u32,
+    cr: u32,
+    key_stage: u8,
+    /// A wrong key locks FLASH_CR until reset.
+    key_fault: bool,
+    busy: Cell<u32>,
+    /// FLASH_SR polls BSY stays set for after each operation.
+    pub busy_polls: u32,
+    /// Never clear BSY, to exercise the timeout path.
+    pub stuck_busy: bool,
+    /// Bitmask of write-protected sectors (nWRP cleared).
+    pub write_protected: u32,
+    /// Number of sector erases performed.
+    pub erase_count: usize,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl MockFlashController {
+    pub fn new(sectors: &'static [SectorRegion]) -> Self {
+        MockFlashController {
+            memory: vec![0xFFu8; map_size(sectors)],
+            sectors,
+            acr: 0,
+            sr: 0,
+            cr: CR_LOCK,
+            key_stage: 0,
+            key_fault: false,
+            busy: Cell::new(0),
+            busy_polls: 3,
+            stuck_busy: false,
+            write_protected: 0,
+            erase_count: 0,
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.cr & CR_LOCK != 0
+    }
+
+    fn start_busy(&mut self) {
+        self.busy.set(self.busy_polls);
+    }
+
+    fn write_cr(&mut self, val: u32) {
+        if self.is_locked() {
+            return;
+        }
+        self.cr = val;
+        if val & CR_STRT != 0 && val & CR_SER != 0 {
+            let n = ((val & CR_SNB_MASK) >> CR_SNB_SHIFT) as usize;
+            self.cr &= !CR_STRT;
+            match sectors(self.sectors).nth(n) {
+                None => self.sr |= SR_OPERR,
+                Some(_) if self.write_protected & (1 << n) != 0 => self.sr |= SR_WRPERR,
+                Some(sector) => {
+                    self.memory[sector.start..sector.end()].fill(0xFF);
+                    self.erase_count += 1;
+                    self.sr |= SR_EOP;
+                }
+            }
+            self.start_busy();
+        }
+    }
+
+    fn program(&mut self, addr: usize, bytes: &[u8]) {
+        if self.cr & CR_PG == 0 || self.is_locked() {
+            self.sr |= SR_PGSERR;
+            return;
+        }
+        let psize = ((self.cr >> CR_PSIZE_SHIFT) & 0b11) as usize;
+        if bytes.len() != 1 << psize {
+            self.sr |= SR_PGPERR;
+            return;
+        }
+        let offset = addr - FLASH_MEMORY_BASE;
+        if !offset.is_multiple_of(bytes.len()) {
+            self.sr |= SR_PGAERR;
+            return;
+        }
+        let sector = sectors(self.sectors).find(|s| offset < s.end());
+        if sector.is_some_and(|s| self.write_protected & (1 << s.index) != 0) {
+            self.sr |= SR_WRPERR;
+            return;
+        }
+        for (i, &b) in bytes.iter().enumerate() {
+            self.memory[offset + i] &= b;
+        }
+        self.sr |= SR_EOP;
+        self.start_busy();
+    }
+
+    fn is_memory(&self, addr: usize) -> bool {
+        (FLASH_MEMORY_BASE..FLASH_MEMORY_BASE + self.memory.len()).contains(&addr)
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Mmio for MockFlashController {
+    fn read8(&self, addr: usize) -> u8 {
+        assert!(self.is_memory(addr), "mock: read8 outside flash at {:#010x}", addr);
+        self.memory[addr - FLASH_MEMORY_BASE]
+    }
+
+    fn read32(&self, addr: usize) -> u32 {
+        match addr {
+            ACR => self.acr,
+            SR => {
+                let busy = self.busy.get();
+                if self.stuck_busy {
+                    self.sr | SR_BSY
+                } else if busy > 0 {
+                    self.busy.set(busy - 1);
+                    self.sr | SR_BSY
+                } else {
+                    self.sr
+                }
+            }
+            CR => self.cr,
+            _ => panic!("mock: unmapped read32 at {:#010x}", addr),
+        }
+    }
+
+    fn write8(&mut self, addr: usize, val: u8) {
+        assert!(self.is_memory(addr), "mock: write8 outside flash at {:#010x}", addr);
+        self.program(addr, &[val]);
+    }
+
+    fn write16(&mut self, addr: usize, val: u16) {
+        assert!(self.is_memory(addr), "mock: write16 outside flash at {:#010x}", addr);
+        self.program(addr, &val.to_le_bytes());
+    }
+
+    fn write32(&mut self, addr: usize, val: u32) {
+        match addr {
+            ACR => self.acr = val & !ACR_DCRST,
+            KEYR => {
+                match (self.key_stage, val) {
+                    _ if self.key_fault => {}
+                    (0, KEY1) => self.key_stage = 1,
+                    (1, KEY2) if self.is_locked() => {
+                        self.cr &= !CR_LOCK;
+                        self.key_stage = 0;
+                    }
+                    _ => self.key_fault = true,
+                }
+            }
+            SR => self.sr &= !(val & (SR_ERRORS | SR_EOP)),
+            CR => self.write_cr(val),
+            a if self.is_memory(a) => self.program(a, &val.to_le_bytes()),
+            _ => panic!("mock: unmapped write32 at {:#010x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn driver(psize: ProgramSize) -> InternalFlash<MockFlashController> {
+        InternalFlash::new(MockFlashController::new(&STM32F411_SECTORS), FLASH_MEMORY_BASE, &STM32F411_SECTORS, 256, psize)
+    }
+
+    #[test]
+    fn sector_map_matches_f411() {
+        let f = driver(ProgramSize::X32);
+        assert_eq!(f.size(), 512 * 1024);
+        assert_eq!(f.sector_at(0x3FFF).map(|s| (s.index, s.start)), Some((0, 0)));
+        assert_eq!(f.sector_at(0x1_0000).map(|s| (s.index, s.size)), Some((4, 0x1_0000)));
+        assert_eq!(f.sector_at(0x6_0000).map(|s| (s.index, s.start)), Some((7, 0x6_0000)));
+        assert_eq!(f.sector_at(0x8_0000), None);
+    }
+
+    #[test]
+    fn erase_and_program_relock() {
+        let mut f = driver(ProgramSize::X32);
+        f.mmio.memory[0x2_0000] = 0x00;
+        f.erase_sector(0x2_0000).unwrap();
+        assert_eq!(f.mmio().memory[0x2_0000], 0xFF);
+        assert_eq!(f.mmio().erase_count, 1);
+        assert!(f.mmio().is_locked());
+
+        // Unaligned head and tail are padded to whole words.
+        let data: Vec<u8> = (0..13u8).collect();
+        f.program_page(0x2_0001, &data).unwrap();
+        assert!(f.verify(0x2_0001, &data).is_ok());
+        assert_eq!(f.mmio().memory[0x2_0000], 0xFF);
+        assert_eq!(f.mmio().memory[0x2_000E], 0xFF);
+        assert!(f.mmio().is_locked());
+
+        assert_eq!(f.erase_sector(0x2_0004), Err(FlashError::AlignmentError));
+    }
+
+    #[test]
+    fn write_region_erases_every_touched_sector() {
+        let mut f = driver(ProgramSize::X16);
+        // Spans sectors 3 (16 KiB) and 4 (64 KiB).
+        let data: Vec<u8> = (0..1024u32).map(|i| (i * 3) as u8).collect();
+        f.write_region(0xFE00, &data).unwrap();
+        assert_eq!(f.mmio().erase_count, 2);
+        assert!(f.verify(0xFE00, &data).is_ok());
+    }
+
+    #[test]
+    fn sr_errors_are_decoded() {
+        let mut f = driver(ProgramSize::X32);
+        f.mmio.write_protected = 1 << 1;
+        assert_eq!(f.erase_sector(0x4000), Err(FlashError::WriteProtected));
+        assert_eq!(f.program_page(0x4000, &[0u8; 4]), Err(FlashError::WriteProtected));
+
+        // A word write while the controller is configured for x8.
+        let wrong_width = f.unlocked(|f| {
+            f.mmio.write32(CR, CR_PG | ((ProgramSize::X8 as u32) << CR_PSIZE_SHIFT));
+            f.program_unit(FLASH_MEMORY_BASE, &[0; 4])
+        });
+        assert_eq!(wrong_width, Err(FlashError::ProgramParallelism));
+
+        // Programming without PG set.
+        let no_pg = f.unlocked(|f| {
+            f.mmio.write32(FLASH_MEMORY_BASE, 0);
+            f.take_errors()
+        });
+        assert_eq!(no_pg, Err(FlashError::ProgramSequence));
+
+        // Misaligned word.
+        let misaligned = f.unlocked(|f| {
+            f.mmio.write32(CR, CR_PG | ((ProgramSize::X32 as u32) << CR_PSIZE_SHIFT));
+            f.program_unit(FLASH_MEMORY_BASE + 2, &[0; 4])
+        });
+        assert_eq!(misaligned, Err(FlashError::ProgramAlignment));
+
+        // Errors were cleared: the next operation succeeds.
+        f.erase_sector(0).unwrap();
+    }
+
+    #[test]
+    fn busy_timeout_and_bad_key() {
+        let mut f = driver(ProgramSize::X32).with_timeout(100);
+        f.mmio.stuck_busy = true;
+        assert_eq!(f.erase_sector(0), Err(FlashError::Timeout));
+
+        let mut f = driver(ProgramSize::X32);
+        f.mmio.write32(KEYR, 0xDEAD_BEEF);
+        assert_eq!(f.erase_sector(0), Err(FlashError::Locked));
+    }
+}
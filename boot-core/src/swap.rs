@@ -0,0 +1,451 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Power-fail-safe image swap between the primary and secondary slots.
+//!
+//! Two strategies, picked at build time:
+//!
+//! - swap-scratch (default): the slots are exchanged one sector at a time
+//!   through a scratch area, in three steps per sector:
+//!   1. secondary sector -> scratch
+//!   2. primary sector   -> secondary sector
+//!   3. scratch          -> primary sector
+//! - swap-move (`swap-move` feature): no scratch area. The primary slot has
+//!   one spare sector at its end. The primary image is first moved up by
+//!   one sector, top sector first; then for each sector the new image is
+//!   copied down into the gap and the old sector up into the secondary slot:
+//!   1. primary sector i     -> primary sector i + 1   (n steps)
+//!   2. secondary sector i   -> primary sector i
+//!   3. primary sector i + 1 -> secondary sector i
+//!
+//!   Sectors must be uniform across both slots.
+//!
+//! Each step erases its destination and reads a source the step does not
+//! touch, so it can be redone from the start any number of times. Both
+//! strategies take three steps per sector. Progress is kept in the status
+//! area, one word per completed step, written only after the step finished.
+//! After a reset, [`resume`] redoes the first step without a record and
+//! carries on, so a swap always runs to completion and never leaves a
+//! half-written image behind.
+//!
+//! Status area layout (little-endian words):
+//!
+//! ```text
+//! 0x00  magic     SWAP_MAGIC, zeroed once the swap has completed
+//! 0x04  length    bytes of each slot being swapped
+//! 0x08  !length   guards against a torn header
+//! 0x0C  reserved
+//! 0x10  step[n]   0xFFFF_FFFF = pending, anything else = done
+//! ```
+
+use crate::flash::{Flash, FlashError, Result, READ_CHUNK_SIZE};
+use crate::slot::SlotLayout;
+
+/// Marks a status area holding a swap in progress ("SWAP").
+pub const SWAP_MAGIC: u32 = 0x5041_5753;
+
+const STATUS_HEADER_LEN: usize = 0x10;
+const STEP_ENTRY_LEN: usize = 4;
+const STEPS_PER_SECTOR: usize = 3;
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+const STEP_DONE: u32 = 0;
+
+/// Status area bytes needed to swap `sectors` sectors.
+pub const fn status_len(sectors: usize) -> usize {
+    STATUS_HEADER_LEN + sectors * STEPS_PER_SECTOR * STEP_ENTRY_LEN
+}
+
+/// Exchange the first `len` bytes (rounded up to whole sectors) of the
+/// primary and secondary slots.
+pub fn swap_slots(flash: &mut dyn Flash, layout: &SlotLayout, len: usize) -> Result<()> {
+    if len == 0 || len > layout.primary.size || len > layout.secondary.size {
+        return Err(FlashError::OutOfBounds);
+    }
+    if status_len(sector_count(flash, layout, len)?) > layout.status.size {
+        return Err(FlashError::OutOfBounds);
+    }
+
+    let status = layout.status.addr;
+    flash.erase_range(status, layout.status.size)?;
+    let mut header = [0xFFu8; STATUS_HEADER_LEN];
+    header[0x00..0x04].copy_from_slice(&SWAP_MAGIC.to_le_bytes());
+    header[0x04..0x08].copy_from_slice(&(len as u32).to_le_bytes());
+    header[0x08..0x0C].copy_from_slice(&(!(len as u32)).to_le_bytes());
+    flash.program_region(status, &header)?;
+
+    run(flash, layout, len, 0)
+}
+
+/// Finish a swap interrupted by a reset.
+///
+/// Returns `Ok(true)` if a swap was in progress and has now completed,
+/// `Ok(false)` if there was nothing to do.
+pub fn resume(flash: &mut dyn Flash, layout: &SlotLayout) -> Result<bool> {
+    let len = match in_progress(flash, layout)? {
+        Some(len) => len,
+        None => return Ok(false),
+    };
+
+    let steps = sector_count(flash, layout, len)? * STEPS_PER_SECTOR;
+    let mut next = 0;
+    while next < steps && read_word(flash, step_addr(layout, next))? != ERASED_WORD {
+        next += 1;
+    }
+    run(flash, layout, len, next)?;
+    Ok(true)
+}
+
+/// Length recorded by an unfinished swap, if any.
+pub fn in_progress(flash: &dyn Flash, layout: &SlotLayout) -> Result<Option<usize>> {
+    let status = layout.status.addr;
+    if read_word(flash, status)? != SWAP_MAGIC {
+        return Ok(None);
+    }
+    let len = read_word(flash, status + 0x04)?;
+    let check = read_word(flash, status + 0x08)?;
+    if check != !len || len == 0 || len as usize > layout.primary.size {
+        // Header torn before the first step started: nothing was moved.
+        return Ok(None);
+    }
+    Ok(Some(len as usize))
+}
+
+/// Run the swap from step `next` to the end and retire the status area.
+#[cfg(not(feature = "swap-move"))]
+fn run(flash: &mut dyn Flash, layout: &SlotLayout, len: usize, next: usize) -> Result<()> {
+    let (primary, secondary, scratch) = (layout.primary.addr, layout.secondary.addr, layout.scratch.addr);
+    let mut offset = 0;
+    let mut step = 0;
+    while offset < len {
+        let size = sector_len(flash, layout, offset)?;
+        for (from, to) in [(secondary + offset, scratch), (primary + offset, secondary + offset), (scratch, primary + offset)] {
+            run_step(flash, layout, &mut step, next, from, to, size)?;
+        }
+        offset += size;
+    }
+    retire(flash, layout)
+}
+
+/// Run the swap from step `next` to the end and retire the status area.
+#[cfg(feature = "swap-move")]
+fn run(flash: &mut dyn Flash, layout: &SlotLayout, len: usize, next: usize) -> Result<()> {
+    let size = move_sector_len(flash, layout)?;
+    let sectors = len.div_ceil(size);
+    let (primary, secondary) = (layout.primary.addr, layout.secondary.addr);
+    let mut step = 0;
+    // Shift the primary image up by one sector, top sector first.
+    for i in (0..sectors).rev() {
+        run_step(flash, layout, &mut step, next, primary + i * size, primary + (i + 1) * size, size)?;
+    }
+    // New sector down into the gap, old sector up into the secondary slot.
+    for i in 0..sectors {
+        run_step(flash, layout, &mut step, next, secondary + i * size, primary + i * size, size)?;
+        run_step(flash, layout, &mut step, next, primary + (i + 1) * size, secondary + i * size, size)?;
+    }
+    retire(flash, layout)
+}
+
+/// Copy `from` to `to` unless step `step` finished before the reset, then
+/// record it as done.
+fn run_step(
+    flash: &mut dyn Flash,
+    layout: &SlotLayout,
+    step: &mut usize,
+    next: usize,
+    from: usize,
+    to: usize,
+    size: usize,
+) -> Result<()> {
+    if *step >= next {
+        copy(flash, from, to, size)?;
+        flash.program_region(step_addr(layout, *step), &STEP_DONE.to_le_bytes())?;
+    }
+    *step += 1;
+    Ok(())
+}
+
+fn retire(flash: &mut dyn Flash, layout: &SlotLayout) -> Result<()> {
+    // Invalidate the header before erasing, so a reset during the erase
+    // cannot resurrect a half-erased step record.
+    flash.program_region(layout.status.addr, &0u32.to_le_bytes())?;
+    flash.erase_range(layout.status.addr, layout.status.size)
+}
+
+/// Size of the primary sector at `offset` into the slot, checking the
+/// secondary slot has the same sector there and the scratch area can hold it.
+#[cfg(not(feature = "swap-move"))]
+fn sector_len(flash: &dyn Flash, layout: &SlotLayout, offset: usize) -> Result<usize> {
+    let p = flash.sector_at(layout.primary.addr + offset).ok_or(FlashError::OutOfBounds)?;
+    let s = flash.sector_at(layout.secondary.addr + offset).ok_or(FlashError::OutOfBounds)?;
+    if p.start != layout.primary.addr + offset || s.start != layout.secondary.addr + offset || p.size != s.size {
+        return Err(FlashError::AlignmentError);
+    }
+    if p.size > layout.scratch.size {
+        return Err(FlashError::OutOfBounds);
+    }
+    Ok(p.size)
+}
+
+/// Sector size shared by every sector of both slots. The primary slot must
+/// have room for the secondary slot plus one spare sector.
+#[cfg(feature = "swap-move")]
+pub(crate) fn move_sector_len(flash: &dyn Flash, layout: &SlotLayout) -> Result<usize> {
+    let size = flash.sector_at(layout.primary.addr).ok_or(FlashError::OutOfBounds)?.size;
+    for slot in [layout.primary, layout.secondary] {
+        let mut offset = 0;
+        while offset < slot.size {
+            let sector = flash.sector_at(slot.addr + offset).ok_or(FlashError::OutOfBounds)?;
+            if sector.start != slot.addr + offset || sector.size != size {
+                return Err(FlashError::AlignmentError);
+            }
+            offset += size;
+        }
+    }
+    if layout.primary.size < layout.secondary.size + size {
+        return Err(FlashError::OutOfBounds);
+    }
+    Ok(size)
+}
+
+#[cfg(feature = "swap-move")]
+fn sector_count(flash: &dyn Flash, layout: &SlotLayout, len: usize) -> Result<usize> {
+    Ok(len.div_ceil(move_sector_len(flash, layout)?))
+}
+
+#[cfg(not(feature = "swap-move"))]
+fn sector_count(flash: &dyn Flash, layout: &SlotLayout, len: usize) -> Result<usize> {
+    let mut offset = 0;
+    let mut count = 0;
+    while offset < len {
+        offset += sector_len(flash, layout, offset)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn step_addr(layout: &SlotLayout, step: usize) -> usize {
+    layout.status.addr + STATUS_HEADER_LEN + step * STEP_ENTRY_LEN
+}
+
+fn read_word(flash: &dyn Flash, addr: usize) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    flash.read(addr, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+/// Erase `len` bytes at `to` and copy `from` into it.
+fn copy(flash: &mut dyn Flash, from: usize, to: usize, len: usize) -> Result<()> {
+    flash.erase_range(to, len)?;
+    let mut buf = [0u8; READ_CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < len {
+        let chunk = core::cmp::min(buf.len(), len - offset);
+        flash.read(from + offset, &mut buf[..chunk])?;
+        flash.program_region(to + offset, &buf[..chunk])?;
+        offset += chunk;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::{MockFlash, SectorRegion};
+    use crate::slot::Slot;
+
+    const IMAGE_LEN: usize = 0x400;
+
+    // status | primary | secondary | scratch, with 4 x 256-byte slot sectors.
+    #[cfg(not(feature = "swap-move"))]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x100, 0x400),
+        Slot::new(0x500, 0x400),
+        Slot::new(0x900, 0x100),
+        Slot::new(0x000, 0x100),
+    );
+
+    // status | primary (4 + 1 spare sectors) | secondary, no scratch.
+    #[cfg(feature = "swap-move")]
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x100, 0x500),
+        Slot::new(0x600, 0x400),
+        Slot::new(0xA00, 0),
+        Slot::new(0x000, 0x100),
+    );
+
+    fn filled() -> MockFlash {
+        let mut f = MockFlash::new(0xA00, 0x100, 0x40);
+        let a: Vec<u8> = (0..IMAGE_LEN as u32).map(|i| i as u8).collect();
+        let b: Vec<u8> = (0..IMAGE_LEN as u32).map(|i| (i * 7 + 3) as u8).collect();
+        f.write_region(LAYOUT.primary.addr, &a).unwrap();
+        f.write_region(LAYOUT.secondary.addr, &b).unwrap();
+        f
+    }
+
+    /// The primary and secondary images.
+    fn images(f: &MockFlash) -> (&[u8], &[u8]) {
+        let (p, s) = (LAYOUT.primary.addr, LAYOUT.secondary.addr);
+        (&f.storage[p..p + IMAGE_LEN], &f.storage[s..s + IMAGE_LEN])
+    }
+
+    fn assert_swapped(f: &MockFlash, original: &MockFlash) {
+        let (p, s) = images(original);
+        assert_eq!(images(f), (s, p));
+        assert_eq!(in_progress(f, &LAYOUT), Ok(None));
+    }
+
+    #[test]
+    fn swap_exchanges_slots() {
+        let original = filled();
+        let mut f = filled();
+        swap_slots(&mut f, &LAYOUT, IMAGE_LEN).unwrap();
+        assert_swapped(&f, &original);
+        assert_eq!(resume(&mut f, &LAYOUT), Ok(false));
+
+        // Swapping again restores the original images.
+        swap_slots(&mut f, &LAYOUT, IMAGE_LEN).unwrap();
+        assert_eq!(images(&f), images(&original));
+    }
+
+    /// Erase and program operations a whole swap of `filled()` takes.
+    fn swap_ops() -> usize {
+        let mut f = filled();
+        let before = f.ops();
+        swap_slots(&mut f, &LAYOUT, IMAGE_LEN).unwrap();
+        f.ops() - before
+    }
+
+    #[test]
+    fn power_loss_at_every_operation_resumes() {
+        let original = filled();
+        for cut in 0..swap_ops() {
+            let mut f = filled();
+            f.seed_noise(cut as u32 + 1);
+            f.cut_power_after(cut);
+            assert_eq!(swap_slots(&mut f, &LAYOUT, IMAGE_LEN), Err(MockFlash::POWER_LOSS));
+            f.reboot();
+            let resumed = resume(&mut f, &LAYOUT).unwrap();
+            // Unless the swap resumed, power was lost before the status header
+            // was written in full (nothing moved) or while the swap was being
+            // retired (everything moved).
+            if resumed || images(&f) != images(&original) {
+                assert_swapped(&f, &original);
+            }
+        }
+    }
+
+    #[test]
+    fn torn_status_writes_are_recovered() {
+        let original = filled();
+        // The swap starts by erasing the status area and programming its
+        // header, so the second operation is the header write.
+        let mut f = filled();
+        f.cut_power_after(1);
+        assert!(swap_slots(&mut f, &LAYOUT, IMAGE_LEN).is_err());
+        f.reboot();
+        // A torn header never reads as a swap in progress.
+        assert_eq!(in_progress(&f, &LAYOUT), Ok(None));
+        assert_eq!(images(&f), images(&original));
+
+        let steps = sector_count(&f, &LAYOUT, IMAGE_LEN).unwrap() * STEPS_PER_SECTOR;
+        let records: Vec<usize> = (2..swap_ops())
+            .filter(|&cut| {
+                let mut f = filled();
+                f.cut_power_after(cut);
+                let _ = swap_slots(&mut f, &LAYOUT, IMAGE_LEN);
+                f.reboot();
+                in_progress(&f, &LAYOUT).unwrap().is_some()
+                    && (0..steps).any(|step| {
+                        let word = read_word(&f, step_addr(&LAYOUT, step)).unwrap();
+                        word != ERASED_WORD && word != STEP_DONE
+                    })
+            })
+            .collect();
+        // Every step record can be torn, and each is resumed from.
+        assert_eq!(records.len(), steps);
+        for cut in records {
+            let mut f = filled();
+            f.cut_power_after(cut);
+            let _ = swap_slots(&mut f, &LAYOUT, IMAGE_LEN);
+            f.reboot();
+            assert_eq!(resume(&mut f, &LAYOUT), Ok(true));
+            assert_swapped(&f, &original);
+        }
+    }
+
+    #[test]
+    fn swap_wear_stays_bounded() {
+        const SWAPS: u32 = 10;
+        let mut f = filled();
+        f.reset_wear();
+        for _ in 0..SWAPS {
+            swap_slots(&mut f, &LAYOUT, IMAGE_LEN).unwrap();
+        }
+
+        // The status sector is erased when a swap starts and when it is
+        // retired, never per step.
+        assert_eq!(f.erase_count(LAYOUT.status.addr), 2 * SWAPS);
+        let mut wear = f.hottest_sectors(usize::MAX).into_iter();
+        // The scratch sector takes one erase per slot sector swapped and
+        // wears out first; size it for that.
+        #[cfg(not(feature = "swap-move"))]
+        {
+            let scratch = wear.next().unwrap();
+            assert_eq!(scratch.sector.start, LAYOUT.scratch.addr);
+            assert_eq!(scratch.erases, 4 * SWAPS);
+        }
+        assert!(wear.all(|w| w.erases <= 2 * SWAPS));
+    }
+
+    #[test]
+    fn power_loss_during_resume_resumes_again() {
+        let original = filled();
+        let ops = swap_ops();
+        // From the first cut past the status header on.
+        for first in (2..ops).step_by(7) {
+            for second in 0..ops {
+                let mut f = filled();
+                f.seed_noise((first * ops + second) as u32 + 1);
+                f.cut_power_after(first);
+                assert_eq!(swap_slots(&mut f, &LAYOUT, IMAGE_LEN), Err(MockFlash::POWER_LOSS));
+                f.reboot();
+                f.cut_power_after(second);
+                let interrupted = resume(&mut f, &LAYOUT).is_err();
+                f.reboot();
+                resume(&mut f, &LAYOUT).unwrap();
+                assert_swapped(&f, &original);
+                if !interrupted {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "swap-move"))]
+    fn mismatched_geometry_is_refused() {
+        let map = [SectorRegion::new(5, 0x100), SectorRegion::new(1, 0x200), SectorRegion::new(3, 0x100)];
+        let mut f = MockFlash::with_sectors(&map, 0x40);
+        assert_eq!(swap_slots(&mut f, &LAYOUT, IMAGE_LEN), Err(FlashError::AlignmentError));
+        assert_eq!(in_progress(&f, &LAYOUT), Ok(None));
+    }
+
+    #[test]
+    #[cfg(feature = "swap-move")]
+    fn move_needs_uniform_sectors_and_a_spare() {
+        let map = [SectorRegion::new(4, 0x100), SectorRegion::new(1, 0x200), SectorRegion::new(4, 0x100)];
+        let mut f = MockFlash::with_sectors(&map, 0x40);
+        assert_eq!(swap_slots(&mut f, &LAYOUT, IMAGE_LEN), Err(FlashError::AlignmentError));
+
+        let mut f = filled();
+        let no_spare = SlotLayout { primary: Slot::new(0x100, 0x400), ..LAYOUT };
+        assert_eq!(swap_slots(&mut f, &no_spare, IMAGE_LEN), Err(FlashError::OutOfBounds));
+        assert_eq!(in_progress(&f, &LAYOUT), Ok(None));
+    }
+}
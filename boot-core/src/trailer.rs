@@ -0,0 +1,248 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Per-slot image state trailer.
+//!
+//! The last `TRAILER_LEN` bytes of each slot's swapped area record what
+//! became of the image in that slot. Images may not extend into it (see
+//! `SlotLayout::image_capacity`). Since a full-slot swap moves the trailer
+//! together with its image, the state follows the image from slot to slot.
+//!
+//! The trailer is an append-only log of state words: a new state is
+//! programmed into the first erased word, and the last recognised word is
+//! the current state. Changing state therefore never needs an erase, and a
+//! reset while programming leaves the previous state in force. The trailer
+//! is erased along with its sector whenever a new image is staged.
+//!
+//! Every boot of an unconfirmed image appends another `Testing` entry, so
+//! the entries double as a boot-attempt counter that costs one word program
+//! per boot and no erase.
+//!
+//! ```text
+//! Pending -> Testing (once per boot attempt) -> Confirmed
+//!                                            -> Reverted
+//! ```
+
+use core::fmt;
+
+use crate::flash::{Flash, FlashError, Result};
+use crate::slot::{Slot, SlotLayout};
+
+/// Bytes reserved for the trailer at the end of each slot's swapped area.
+pub const TRAILER_LEN: usize = 0x40;
+
+const ENTRY_LEN: usize = 4;
+const ERASED_WORD: u32 = 0xFFFF_FFFF;
+
+/// Most boot attempts the trailer can count, leaving room for the `Pending`
+/// entry before them and the `Confirmed` or `Reverted` entry after.
+pub const MAX_BOOT_ATTEMPTS: usize = TRAILER_LEN / ENTRY_LEN - 2;
+
+/// What became of the image in a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageState {
+    /// Verified in the secondary slot and about to be installed on trial.
+    Pending,
+    /// Installed and booted on trial; reverted unless confirmed within the
+    /// allowed boot attempts.
+    Testing,
+    /// Confirmed by the application, kept from now on.
+    Confirmed,
+    /// Failed its trial; never installed again.
+    Reverted,
+}
+
+impl ImageState {
+    const fn word(self) -> u32 {
+        match self {
+            ImageState::Pending => 0x504E_4450,
+            ImageState::Testing => 0x5453_4554,
+            ImageState::Confirmed => 0x434E_4643,
+            ImageState::Reverted => 0x5256_5254,
+        }
+    }
+
+    fn from_word(word: u32) -> Option<Self> {
+        [ImageState::Pending, ImageState::Testing, ImageState::Confirmed, ImageState::Reverted]
+            .into_iter()
+            .find(|s| s.word() == word)
+    }
+}
+
+impl fmt::Display for ImageState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageState::Pending => write!(f, "pending"),
+            ImageState::Testing => write!(f, "testing"),
+            ImageState::Confirmed => write!(f, "confirmed"),
+            ImageState::Reverted => write!(f, "reverted"),
+        }
+    }
+}
+
+/// Address of the trailer of `slot`.
+pub fn trailer_addr(layout: &SlotLayout, slot: Slot) -> usize {
+    slot.addr + layout.image_capacity()
+}
+
+/// Current state of the image in `slot`; `None` for an erased trailer, such
+/// as that of a factory-programmed image.
+pub fn read_state(flash: &dyn Flash, layout: &SlotLayout, slot: Slot) -> Result<Option<ImageState>> {
+    let addr = trailer_addr(layout, slot);
+    let mut state = None;
+    for entry in 0..TRAILER_LEN / ENTRY_LEN {
+        let word = read_word(flash, addr + entry * ENTRY_LEN)?;
+        if word == ERASED_WORD {
+            break;
+        }
+        // Anything else was torn by a reset while programming.
+        if let Some(s) = ImageState::from_word(word) {
+            state = Some(s);
+        }
+    }
+    Ok(state)
+}
+
+/// Number of times the image in `slot` was booted on trial since it was
+/// installed.
+pub fn boot_attempts(flash: &dyn Flash, layout: &SlotLayout, slot: Slot) -> Result<usize> {
+    let addr = trailer_addr(layout, slot);
+    let mut attempts = 0;
+    for entry in 0..TRAILER_LEN / ENTRY_LEN {
+        let word = read_word(flash, addr + entry * ENTRY_LEN)?;
+        if word == ERASED_WORD {
+            break;
+        }
+        if word == ImageState::Testing.word() {
+            attempts += 1;
+        }
+    }
+    Ok(attempts)
+}
+
+/// Record `state` for the image in `slot`.
+///
+/// Fails with `FlashError::OutOfBounds` once every trailer entry is used.
+pub fn write_state(flash: &mut dyn Flash, layout: &SlotLayout, slot: Slot, state: ImageState) -> Result<()> {
+    let addr = trailer_addr(layout, slot);
+    for entry in 0..TRAILER_LEN / ENTRY_LEN {
+        let entry_addr = addr + entry * ENTRY_LEN;
+        if read_word(flash, entry_addr)? == ERASED_WORD {
+            return flash.program_region(entry_addr, &state.word().to_le_bytes());
+        }
+    }
+    Err(FlashError::OutOfBounds)
+}
+
+/// Keep the image running from the primary slot. Called by the application
+/// once it is satisfied the new image works.
+pub fn confirm(flash: &mut dyn Flash, layout: &SlotLayout) -> Result<()> {
+    if read_state(flash, layout, layout.primary)? == Some(ImageState::Confirmed) {
+        return Ok(());
+    }
+    write_state(flash, layout, layout.primary, ImageState::Confirmed)
+}
+
+/// Erase the trailer of `slot`, and with it the rest of its sector.
+pub fn erase(flash: &mut dyn Flash, layout: &SlotLayout, slot: Slot) -> Result<()> {
+    flash.erase_range(trailer_addr(layout, slot), TRAILER_LEN)
+}
+
+fn read_word(flash: &dyn Flash, addr: usize) -> Result<u32> {
+    let mut buf = [0u8; 4];
+    flash.read(addr, &mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MockFlash;
+
+    const LAYOUT: SlotLayout = SlotLayout::new(
+        Slot::new(0x100, 0x100),
+        Slot::new(0x200, 0x100),
+        Slot::new(0x300, 0x100),
+        Slot::new(0x000, 0x100),
+    );
+
+    #[test]
+    fn states_are_appended() {
+        let mut f = MockFlash::new(0x400, 0x100, 0x20);
+        assert_eq!(read_state(&f, &LAYOUT, LAYOUT.primary), Ok(None));
+
+        write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Pending).unwrap();
+        write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Testing).unwrap();
+        write_state(&mut f, &LAYOUT, LAYOUT.primary, ImageState::Testing).unwrap();
+        assert_eq!(read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Testing)));
+        assert_eq!(boot_attempts(&f, &LAYOUT, LAYOUT.primary), Ok(2));
+        confirm(&mut f, &LAYOUT).unwrap();
+        confirm(&mut f, &LAYOUT).unwrap();
+        assert_eq!(read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Confirmed)));
+        // State changes cost one word program each and no erase.
+        assert_eq!(f.erase_count(trailer_addr(&LAYOUT, LAYOUT.primary)), 0);
+        // The other slot has a trailer of its own.
+        assert_eq!(read_state(&f, &LAYOUT, LAYOUT.secondary), Ok(None));
+
+        // A torn entry is skipped, the state before it stays in force.
+        f.program_page(trailer_addr(&LAYOUT, LAYOUT.primary) + 16, &[0x00, 0x12, 0x00, 0x00]).unwrap();
+        assert_eq!(read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(ImageState::Confirmed)));
+
+        erase(&mut f, &LAYOUT, LAYOUT.primary).unwrap();
+        assert_eq!(read_state(&f, &LAYOUT, LAYOUT.primary), Ok(None));
+    }
+
+    #[test]
+    fn full_trailer_is_reported() {
+        let mut f = MockFlash::new(0x400, 0x100, 0x20);
+        for _ in 0..TRAILER_LEN / ENTRY_LEN {
+            write_state(&mut f, &LAYOUT, LAYOUT.secondary, ImageState::Pending).unwrap();
+        }
+        assert_eq!(
+            write_state(&mut f, &LAYOUT, LAYOUT.secondary, ImageState::Testing),
+            Err(FlashError::OutOfBounds)
+        );
+    }
+
+    #[test]
+    fn torn_state_write_keeps_previous_state() {
+        use ImageState::*;
+        let transitions = [(None, Pending), (Some(Pending), Testing), (Some(Testing), Confirmed), (Some(Testing), Reverted)];
+        for (before, after) in transitions {
+            for seed in 1..=64 {
+                let mut f = MockFlash::new(0x400, 0x100, 0x20);
+                let history: &[ImageState] = match before {
+                    None => &[],
+                    Some(Pending) => &[Pending],
+                    Some(_) => &[Pending, Testing],
+                };
+                for &state in history {
+                    write_state(&mut f, &LAYOUT, LAYOUT.primary, state).unwrap();
+                }
+                let attempts = boot_attempts(&f, &LAYOUT, LAYOUT.primary).unwrap();
+
+                f.seed_noise(seed);
+                f.cut_power_after(0);
+                assert_eq!(write_state(&mut f, &LAYOUT, LAYOUT.primary, after), Err(MockFlash::POWER_LOSS));
+                f.reboot();
+
+                // A torn entry never reads as some other state, and is not
+                // counted as a boot attempt.
+                let state = read_state(&f, &LAYOUT, LAYOUT.primary).unwrap();
+                assert!(state == before || state == Some(after), "{before:?} -> {after:?} read as {state:?}");
+                let torn_attempts = boot_attempts(&f, &LAYOUT, LAYOUT.primary).unwrap();
+                assert!(torn_attempts == attempts || (after == Testing && state == Some(Testing)));
+
+                // Writing the state again after the reset takes effect.
+                write_state(&mut f, &LAYOUT, LAYOUT.primary, after).unwrap();
+                assert_eq!(read_state(&f, &LAYOUT, LAYOUT.primary), Ok(Some(after)));
+            }
+        }
+    }
+}
@@ -0,0 +1,431 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+
+//! Firmware update handling module.
+//!
+//! This module manages the process of receiving, validating,
+//! and writing a new firmware image to flash memory. It builds
+//! upon the flash abstraction (`flash.rs`) and verification
+//! routines (`verify.rs`).
+
+use core::marker::PhantomData;
+
+use crate::digest::{DigestType, ExpectedDigest, ImageDigest};
+use crate::flash::{Flash, FlashError};
+use crate::image::{ImageError, ImageHeader};
+use crate::verify::verify_digest;
+
+/// Metadata describing the incoming firmware update.
+#[derive(Debug, Clone, Copy)]
+pub struct UpdateMetadata {
+    /// Absolute start address in flash where the new image will be written.
+    pub target_addr: usize,
+    /// Total size of the firmware image in bytes.
+    pub image_size: usize,
+    /// Offset of the payload inside the image. Zero for raw images, the
+    /// header size for images that start with an [`ImageHeader`].
+    pub payload_offset: usize,
+    /// Expected digest of the payload, tagged with its algorithm.
+    pub expected_digest: ExpectedDigest,
+}
+
+impl UpdateMetadata {
+    /// Build update metadata from a validated image header.
+    ///
+    /// `slot_addr` is the start of the slot the image (header followed by
+    /// payload) is written to.
+    pub fn from_header(header: &ImageHeader, slot_addr: usize) -> Self {
+        UpdateMetadata {
+            target_addr: slot_addr,
+            image_size: header.header_size as usize + header.image_size as usize,
+            payload_offset: header.header_size as usize,
+            expected_digest: header.digest,
+        }
+    }
+
+    /// Flash address of the payload.
+    pub fn payload_addr(&self) -> usize {
+        self.target_addr + self.payload_offset
+    }
+
+    /// Size of the payload in bytes.
+    pub fn payload_size(&self) -> usize {
+        self.image_size - self.payload_offset
+    }
+
+    /// Check the payload in flash with digest algorithm `D`.
+    ///
+    /// Fails with [`UpdateError::UnsupportedDigest`] when the expected digest
+    /// was made with another algorithm than the one this build uses.
+    pub fn verify_payload<D: ImageDigest>(&self, flash: &dyn Flash) -> UpdateResult<()> {
+        let digest_type = self.expected_digest.digest_type();
+        if digest_type != D::TYPE {
+            return Err(UpdateError::UnsupportedDigest(digest_type));
+        }
+        if !verify_digest::<D>(flash, self.payload_addr(), self.payload_size(), &self.expected_digest)? {
+            return Err(UpdateError::DigestMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Possible errors during the update process.
+#[derive(Debug)]
+pub enum UpdateError {
+    Flash(FlashError),
+    Image(ImageError),
+    InvalidSize,
+    UnsupportedDigest(DigestType),
+    DigestMismatch,
+    SignatureInvalid,
+    /// Security version below the device's anti-rollback counter.
+    Rollback(u16),
+    TransferIncomplete,
+    Other(&'static str),
+}
+
+impl From<FlashError> for UpdateError {
+    fn from(e: FlashError) -> Self {
+        UpdateError::Flash(e)
+    }
+}
+
+impl From<ImageError> for UpdateError {
+    fn from(e: ImageError) -> Self {
+        UpdateError::Image(e)
+    }
+}
+
+pub type UpdateResult<T> = core::result::Result<T, UpdateError>;
+
+/// Handles the reception and flashing of a new firmware image.
+///
+/// The updater is generic over the digest algorithm `D` used to check the
+/// image, so a build only carries the algorithm it actually uses.
+///
+/// Typical workflow:
+/// 1. Call [`begin_update`] with metadata to erase target sectors.
+/// 2. Call [`write_chunk`] repeatedly to program image data.
+/// 3. Call [`finalize_update`] to verify the digest and finalize.
+pub struct FirmwareUpdater<'a, D: ImageDigest> {
+    flash: &'a mut dyn Flash,
+    meta: UpdateMetadata,
+    written: usize,
+    _digest: PhantomData<D>,
+}
+
+impl<'a, D: ImageDigest> FirmwareUpdater<'a, D> {
+    /// Prepare for a new firmware update by erasing the target region.
+    pub fn begin_update(flash: &'a mut dyn Flash, meta: UpdateMetadata) -> UpdateResult<Self> {
+        if meta.image_size == 0 || meta.payload_offset >= meta.image_size {
+            return Err(UpdateError::InvalidSize);
+        }
+        let digest_type = meta.expected_digest.digest_type();
+        if digest_type != D::TYPE {
+            return Err(UpdateError::UnsupportedDigest(digest_type));
+        }
+        // The slot must start on a sector boundary, otherwise erasing it
+        // would take out whatever precedes it in the same sector.
+        match flash.sector_at(meta.target_addr) {
+            Some(sector) if sector.start == meta.target_addr => {}
+            Some(_) => return Err(UpdateError::Flash(FlashError::AlignmentError)),
+            None => return Err(UpdateError::Flash(FlashError::OutOfBounds)),
+        }
+        flash.erase_range(meta.target_addr, meta.image_size)?;
+        Ok(FirmwareUpdater { flash, meta, written: 0, _digest: PhantomData })
+    }
+
+    /// Write a contiguous chunk of firmware data.
+    /// The caller must supply chunks aligned to the flash page size. A chunk
+    /// reaching past `image_size` is refused with `UpdateError::InvalidSize`:
+    /// only that much was erased, and what follows it (e.g. the slot
+    /// trailer) is not the sender's to write.
+    pub fn write_chunk(&mut self, offset: usize, data: &[u8]) -> UpdateResult<()> {
+        if offset != self.written {
+            return Err(UpdateError::Other("Offset mismatch"));
+        }
+        if offset.checked_add(data.len()).is_none_or(|end| end > self.meta.image_size) {
+            return Err(UpdateError::InvalidSize);
+        }
+        let abs_addr = self.meta.target_addr + offset;
+        // Sectors were erased by `begin_update`; erasing again here would
+        // wipe earlier chunks sharing the sector.
+        self.flash.program_region(abs_addr, data)?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    /// Verify the written firmware image against the expected digest.
+    ///
+    /// With the `secure-boot` feature the image must also start with an
+    /// [`ImageHeader`] whose signature verifies against the trusted key.
+    pub fn finalize_update(self) -> UpdateResult<()> {
+        if self.written != self.meta.image_size {
+            return Err(UpdateError::TransferIncomplete);
+        }
+        self.meta.verify_payload::<D>(self.flash)?;
+
+        #[cfg(feature = "secure-boot")]
+        {
+            if self.meta.payload_offset == 0 {
+                // A raw image has no header and therefore no signature.
+                return Err(UpdateError::SignatureInvalid);
+            }
+            let header = ImageHeader::read_from(self.flash, self.meta.target_addr, self.meta.image_size)?;
+            crate::signature::verify_image(self.flash, self.meta.target_addr, &header)
+                .map_err(|_| UpdateError::SignatureInvalid)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::digest::Sha256;
+    use crate::flash::MockFlash;
+    use crate::image::{ImageVersion, IMAGE_HEADER_ENCODED_LEN, IMAGE_HEADER_SIZE};
+
+    /// Lay out a header followed by `payload` as one update stream, signed
+    /// with the development key in `secure-boot` builds.
+    fn image_bytes(header: &ImageHeader, payload: &[u8]) -> Vec<u8> {
+        let mut image = vec![0xFFu8; IMAGE_HEADER_SIZE + payload.len()];
+        image[..IMAGE_HEADER_ENCODED_LEN].copy_from_slice(&header.to_bytes());
+        image[IMAGE_HEADER_SIZE..].copy_from_slice(payload);
+        #[cfg(feature = "secure-boot")]
+        {
+            use crate::image::{IMAGE_SIGNATURE_LEN, IMAGE_SIGNATURE_OFFSET};
+            use crate::signature::test_keys::{sign_hash, DEV_SEED};
+
+            let mut tmp = MockFlash::new(image.len().next_multiple_of(1024), 1024, 256);
+            tmp.write_region(0, &image).unwrap();
+            let hash = crate::signature::image_hash(&tmp, 0, header).unwrap();
+            image[IMAGE_SIGNATURE_OFFSET..IMAGE_SIGNATURE_OFFSET + IMAGE_SIGNATURE_LEN]
+                .copy_from_slice(&sign_hash(&DEV_SEED, &hash));
+        }
+        image
+    }
+
+    fn digest_of<D: ImageDigest>(data: &[u8]) -> D::Output {
+        let mut d = D::new();
+        d.update(data);
+        d.finalize()
+    }
+
+    #[test]
+    #[cfg(not(feature = "secure-boot"))]
+    fn test_firmware_update_flow() {
+        use crate::digest::Crc32;
+
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        let data = [0x42u8; 1024];
+        let mut tmp = MockFlash::new(2048, 1024, 256);
+        tmp.write_region(0, &data).unwrap();
+        let expected_crc = tmp.crc32(0, data.len()).unwrap();
+
+        let meta = UpdateMetadata {
+            target_addr: 0,
+            image_size: data.len(),
+            payload_offset: 0,
+            expected_digest: ExpectedDigest::Crc32(expected_crc),
+        };
+
+        let mut updater = FirmwareUpdater::<Crc32>::begin_update(&mut mock, meta).unwrap();
+        updater.write_chunk(0, &data).unwrap();
+        updater.finalize_update().unwrap();
+    }
+
+    #[test]
+    fn test_chunks_on_non_uniform_sectors() {
+        use crate::flash::SectorRegion;
+
+        let map = [SectorRegion::new(2, 512), SectorRegion::new(2, 2048)];
+        let mut mock = MockFlash::with_sectors(&map, 256);
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 13) as u8).collect();
+        let meta = UpdateMetadata {
+            target_addr: 512,
+            image_size: data.len(),
+            payload_offset: 0,
+            expected_digest: ExpectedDigest::Sha256([0u8; 32]),
+        };
+
+        // Several chunks land in the same sector; none may erase another.
+        let mut updater = FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta).unwrap();
+        for (i, chunk) in data.chunks(256).enumerate() {
+            updater.write_chunk(i * 256, chunk).unwrap();
+        }
+        assert!(updater.flash.verify(512, &data).is_ok());
+
+        // A slot that does not start on a sector boundary is refused.
+        let meta = UpdateMetadata { target_addr: 1536, ..meta };
+        assert!(matches!(
+            FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta),
+            Err(UpdateError::Flash(FlashError::AlignmentError))
+        ));
+    }
+
+    #[test]
+    fn test_oversized_chunk_is_refused() {
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        let meta = UpdateMetadata {
+            target_addr: 0,
+            image_size: 0x40,
+            payload_offset: 0,
+            expected_digest: ExpectedDigest::Sha256([0u8; 32]),
+        };
+
+        let mut updater = FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta).unwrap();
+        assert!(matches!(updater.write_chunk(0, &[0u8; 0x80]), Err(UpdateError::InvalidSize)));
+        updater.write_chunk(0, &[0u8; 0x20]).unwrap();
+        assert!(matches!(updater.write_chunk(0x20, &[0u8; 0x21]), Err(UpdateError::InvalidSize)));
+        updater.write_chunk(0x20, &[0u8; 0x20]).unwrap();
+
+        // Nothing past the image was programmed.
+        assert!(mock.storage[0x40..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn test_metadata_from_header() {
+        let header = ImageHeader::with_crc32(0x0800_4200, 2048, ImageVersion::new(1, 0, 0, 0), 0xCAFE_F00D);
+        let meta = UpdateMetadata::from_header(&header, 0x4000);
+        assert_eq!(meta.target_addr, 0x4000);
+        assert_eq!(meta.payload_addr(), 0x4000 + IMAGE_HEADER_SIZE);
+        assert_eq!(meta.payload_size(), 2048);
+        assert_eq!(meta.expected_digest, ExpectedDigest::Crc32(0xCAFE_F00D));
+    }
+
+    #[test]
+    #[cfg(feature = "secure-boot")]
+    fn test_signed_update_flow() {
+        use crate::image::IMAGE_SIGNATURE_OFFSET;
+        use crate::signature::test_keys::{sign_hash, DEV_SEED};
+
+        // Build a signed image in a scratch flash to obtain its hash.
+        let payload = [0x42u8; 1024];
+        let digest = ExpectedDigest::Sha256(digest_of::<Sha256>(&payload));
+        let header = ImageHeader::new(0x0800_4200, payload.len() as u32, ImageVersion::new(1, 0, 0, 0), digest);
+        let image = image_bytes(&header, &payload);
+        let mut tmp = MockFlash::new(4096, 1024, 256);
+        tmp.write_region(0, &image).unwrap();
+        let hash = crate::signature::image_hash(&tmp, 0, &header).unwrap();
+
+        for (seed, accepted) in [(DEV_SEED, true), ([0x11u8; 32], false)] {
+            let sig = sign_hash(&seed, &hash);
+            let mut signed = image.clone();
+            signed[IMAGE_SIGNATURE_OFFSET..IMAGE_SIGNATURE_OFFSET + 64].copy_from_slice(&sig);
+
+            let mut mock = MockFlash::new(4096, 1024, 256);
+            let meta = UpdateMetadata::from_header(&header, 0);
+            let mut updater = FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta).unwrap();
+            updater.write_chunk(0, &signed).unwrap();
+            let result = updater.finalize_update();
+            if accepted {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(UpdateError::SignatureInvalid)));
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "secure-boot"))]
+    fn test_digest_update_flow() {
+        use crate::digest::Sha512;
+
+        let payload = [0x24u8; 1024];
+        let digest = ExpectedDigest::Sha512(digest_of::<Sha512>(&payload));
+        let header = ImageHeader::new(0x0800_4200, payload.len() as u32, ImageVersion::new(1, 1, 0, 0), digest);
+        let mut image = image_bytes(&header, &payload);
+
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        let meta = UpdateMetadata::from_header(&header, 1024);
+        let mut updater = FirmwareUpdater::<Sha512>::begin_update(&mut mock, meta).unwrap();
+        updater.write_chunk(0, &image).unwrap();
+        updater.finalize_update().unwrap();
+
+        // A payload that does not hash to the header digest is refused.
+        image[IMAGE_HEADER_SIZE + 10] = 0x00;
+        let mut updater = FirmwareUpdater::<Sha512>::begin_update(&mut mock, meta).unwrap();
+        updater.write_chunk(0, &image).unwrap();
+        assert!(matches!(updater.finalize_update(), Err(UpdateError::DigestMismatch)));
+    }
+
+    #[test]
+    fn test_power_loss_during_update() {
+        let payload: Vec<u8> = (0..1500u32).map(|i| (i * 3) as u8).collect();
+        let digest = ExpectedDigest::Sha256(digest_of::<Sha256>(&payload));
+        let header = ImageHeader::new(0x0800_4200, payload.len() as u32, ImageVersion::new(1, 0, 0, 0), digest);
+        let image = image_bytes(&header, &payload);
+        let meta = UpdateMetadata::from_header(&header, 1024);
+
+        fn run(flash: &mut MockFlash, meta: UpdateMetadata, image: &[u8]) -> UpdateResult<()> {
+            let mut updater = FirmwareUpdater::<Sha256>::begin_update(flash, meta)?;
+            for (i, chunk) in image.chunks(256).enumerate() {
+                updater.write_chunk(i * 256, chunk)?;
+            }
+            updater.finalize_update()
+        }
+
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        run(&mut mock, meta, &image).unwrap();
+        let steps = mock.ops();
+
+        // Whatever step power is lost at, the partial image never verifies
+        // and the update can be run again from the start.
+        for cut in 0..steps {
+            let mut mock = MockFlash::new(4096, 1024, 256);
+            mock.seed_noise(cut as u32 + 1);
+            mock.cut_power_after(cut);
+            assert!(matches!(run(&mut mock, meta, &image), Err(UpdateError::Flash(e)) if e == MockFlash::POWER_LOSS));
+            mock.reboot();
+            assert!(meta.verify_payload::<Sha256>(&mock).is_err());
+            run(&mut mock, meta, &image).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_bit_flips_are_reported() {
+        use crate::flash::FlipOn;
+
+        let payload = [0x3Cu8; 1024];
+        let digest = ExpectedDigest::Sha256(digest_of::<Sha256>(&payload));
+        let header = ImageHeader::new(0x0800_4200, payload.len() as u32, ImageVersion::new(1, 0, 0, 0), digest);
+        let image = image_bytes(&header, &payload);
+        let meta = UpdateMetadata::from_header(&header, 1024);
+
+        // A bit that does not program right is caught by the write itself,
+        // at its address.
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        mock.flip_bit(1024 + 700, 3, FlipOn::Program);
+        let mut updater = FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta).unwrap();
+        assert!(matches!(
+            updater.write_chunk(0, &image),
+            Err(UpdateError::Flash(FlashError::VerificationFailed { addr: 0x6BC, .. }))
+        ));
+
+        // One that reads back wrong later is caught by the digest.
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        let mut updater = FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta).unwrap();
+        updater.write_chunk(0, &image).unwrap();
+        updater.finalize_update().unwrap();
+        mock.flip_bit(1024 + IMAGE_HEADER_SIZE + 10, 0, FlipOn::Read);
+        assert!(matches!(meta.verify_payload::<Sha256>(&mock), Err(UpdateError::DigestMismatch)));
+    }
+
+    #[test]
+    fn test_digest_algorithm_must_match_build() {
+        let header = ImageHeader::with_crc32(0x0800_4200, 1024, ImageVersion::new(1, 0, 0, 0), 0x1234_5678);
+        let meta = UpdateMetadata::from_header(&header, 0);
+        let mut mock = MockFlash::new(4096, 1024, 256);
+        assert!(matches!(
+            FirmwareUpdater::<Sha256>::begin_update(&mut mock, meta),
+            Err(UpdateError::UnsupportedDigest(DigestType::Crc32))
+        ));
+    }
+}
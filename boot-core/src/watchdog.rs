@@ -0,0 +1,366 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Independent watchdog (IWDG) and reset cause.
+//!
+//! This module provides:
+//! - The `Watchdog` trait and `FedFlash`, a [`Flash`] wrapper that feeds the
+//!   watchdog before every sector erase and page program, so long swaps,
+//!   copies and downloads keep it quiet only while they make progress.
+//! - `Iwdg`, the STM32F4 IWDG driver. Once started the IWDG cannot be
+//!   stopped; it keeps running into the application, which must feed it, so
+//!   a trial boot that hangs is reset and counted as failed.
+//! - `read_reset_cause`, decoding and clearing the RCC_CSR reset flags.
+//! - `MockIwdg`, a register-level model of both for the host.
+
+use core::fmt;
+
+use crate::flash::{Flash, Result as FlashResult, SectorRegion};
+use crate::stm32f4::{Mmio, VolatileMmio};
+
+/// Base address of the IWDG registers.
+pub const IWDG_BASE: usize = 0x4000_3000;
+/// RCC control/status register, holding the reset flags.
+pub const RCC_CSR: usize = 0x4002_3874;
+
+const KR: usize = IWDG_BASE;
+const PR: usize = IWDG_BASE + 0x04;
+const RLR: usize = IWDG_BASE + 0x08;
+const SR: usize = IWDG_BASE + 0x0C;
+
+const KEY_START: u32 = 0xCCCC;
+const KEY_FEED: u32 = 0xAAAA;
+const KEY_UNLOCK: u32 = 0x5555;
+
+const SR_PVU: u32 = 1 << 0;
+const SR_RVU: u32 = 1 << 1;
+
+const CSR_RMVF: u32 = 1 << 24;
+const CSR_BORRSTF: u32 = 1 << 25;
+const CSR_PINRSTF: u32 = 1 << 26;
+const CSR_PORRSTF: u32 = 1 << 27;
+const CSR_SFTRSTF: u32 = 1 << 28;
+const CSR_IWDGRSTF: u32 = 1 << 29;
+const CSR_WWDGRSTF: u32 = 1 << 30;
+const CSR_LPWRRSTF: u32 = 1 << 31;
+
+/// Nominal LSI frequency clocking the IWDG. The real LSI runs anywhere from
+/// 17 to 47 kHz, so leave generous margin in timeouts.
+pub const LSI_HZ: u32 = 32_000;
+const RELOAD_MAX: u32 = 0xFFF;
+const PRESCALER_MAX: u32 = 6;
+
+/// Polls of IWDG_SR before giving up on a register update.
+const UPDATE_POLLS: u32 = 100_000;
+
+/// Something that resets the MCU unless fed in time.
+pub trait Watchdog {
+    fn feed(&mut self);
+}
+
+/// Errors while starting the watchdog.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// Timeout is zero or longer than the IWDG can count.
+    InvalidTimeout(u32),
+    /// A prescaler or reload update never completed.
+    Timeout,
+}
+
+impl fmt::Display for WatchdogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchdogError::InvalidTimeout(ms) => write!(f, "watchdog: unsupported timeout {} ms", ms),
+            WatchdogError::Timeout => write!(f, "watchdog: register update timed out"),
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, WatchdogError>;
+
+/// Why the MCU last reset, from the RCC_CSR flags.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    /// Independent or window watchdog.
+    Watchdog,
+    /// Low-power management reset.
+    LowPower,
+    /// `SYSRESETREQ`, e.g. from the application asking for an update.
+    Software,
+    /// Power-on, or supply dropped below the brown-out level.
+    PowerOn,
+    /// NRST pin.
+    Pin,
+    Unknown,
+}
+
+/// Read the reset cause and clear the flags, so the next reset reports only
+/// its own cause.
+pub fn read_reset_cause<M: Mmio>(mmio: &mut M) -> ResetCause {
+    let csr = mmio.read32(RCC_CSR);
+    mmio.write32(RCC_CSR, csr | CSR_RMVF);
+    // Every internal reset also pulses NRST, so the pin flag comes last.
+    if csr & (CSR_IWDGRSTF | CSR_WWDGRSTF) != 0 {
+        ResetCause::Watchdog
+    } else if csr & CSR_LPWRRSTF != 0 {
+        ResetCause::LowPower
+    } else if csr & CSR_SFTRSTF != 0 {
+        ResetCause::Software
+    } else if csr & (CSR_PORRSTF | CSR_BORRSTF) != 0 {
+        ResetCause::PowerOn
+    } else if csr & CSR_PINRSTF != 0 {
+        ResetCause::Pin
+    } else {
+        ResetCause::Unknown
+    }
+}
+
+/// Prescaler code and reload value for the shortest IWDG period of at least
+/// `timeout_ms`.
+pub const fn iwdg_config(timeout_ms: u32) -> Option<(u32, u32)> {
+    if timeout_ms == 0 {
+        return None;
+    }
+    let ticks = timeout_ms as u64 * (LSI_HZ / 1000) as u64;
+    let mut pr = 0;
+    while pr <= PRESCALER_MAX {
+        let divider = 4u64 << pr;
+        let reload = ticks.div_ceil(divider);
+        if reload <= RELOAD_MAX as u64 + 1 {
+            return Some((pr, reload as u32 - 1));
+        }
+        pr += 1;
+    }
+    None
+}
+
+/// STM32F4 independent watchdog.
+pub struct Iwdg<M: Mmio = VolatileMmio> {
+    mmio: M,
+}
+
+impl<M: Mmio> Iwdg<M> {
+    pub const fn new(mmio: M) -> Self {
+        Self { mmio }
+    }
+
+    /// Start the watchdog with a period of at least `timeout_ms`.
+    ///
+    /// Also reconfigures an IWDG already started (by hardware option bytes
+    /// or an earlier stage).
+    pub fn start(&mut self, timeout_ms: u32) -> Result<()> {
+        let (pr, rlr) = iwdg_config(timeout_ms).ok_or(WatchdogError::InvalidTimeout(timeout_ms))?;
+        self.mmio.write32(KR, KEY_START);
+        self.mmio.write32(KR, KEY_UNLOCK);
+        self.mmio.write32(PR, pr);
+        self.mmio.write32(RLR, rlr);
+        let mut polls = 0;
+        while self.mmio.read32(SR) & (SR_PVU | SR_RVU) != 0 {
+            polls += 1;
+            if polls >= UPDATE_POLLS {
+                return Err(WatchdogError::Timeout);
+            }
+        }
+        // Reload now, with the new period.
+        self.mmio.write32(KR, KEY_FEED);
+        Ok(())
+    }
+
+    #[cfg(any(test, feature = "std"))]
+    pub fn mmio(&self) -> &M {
+        &self.mmio
+    }
+}
+
+impl<M: Mmio> Watchdog for Iwdg<M> {
+    fn feed(&mut self) {
+        self.mmio.write32(KR, KEY_FEED);
+    }
+}
+
+/// Feeds `watchdog` before each erase and program on `flash`.
+pub struct FedFlash<'a> {
+    flash: &'a mut dyn Flash,
+    watchdog: &'a mut dyn Watchdog,
+}
+
+impl<'a> FedFlash<'a> {
+    pub fn new(flash: &'a mut dyn Flash, watchdog: &'a mut dyn Watchdog) -> Self {
+        Self { flash, watchdog }
+    }
+}
+
+impl Flash for FedFlash<'_> {
+    fn size(&self) -> usize {
+        self.flash.size()
+    }
+
+    fn sector_map(&self) -> &[SectorRegion] {
+        self.flash.sector_map()
+    }
+
+    fn page_size(&self) -> usize {
+        self.flash.page_size()
+    }
+
+    fn read(&self, addr: usize, buf: &mut [u8]) -> FlashResult<()> {
+        self.flash.read(addr, buf)
+    }
+
+    fn erase_sector(&mut self, addr: usize) -> FlashResult<()> {
+        self.watchdog.feed();
+        self.flash.erase_sector(addr)
+    }
+
+    fn program_page(&mut self, addr: usize, data: &[u8]) -> FlashResult<()> {
+        self.watchdog.feed();
+        self.flash.program_page(addr, data)
+    }
+}
+
+/// Register-level model of the IWDG and RCC_CSR.
+#[cfg(any(test, feature = "std"))]
+#[derive(Debug, Default)]
+pub struct MockIwdg {
+    pub running: bool,
+    pub unlocked: bool,
+    pub pr: u32,
+    pub rlr: u32,
+    /// SR polls an update stays pending for.
+    pub update_polls: core::cell::Cell<u32>,
+    pub feeds: usize,
+    pub csr: u32,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl MockIwdg {
+    /// Period in milliseconds at the nominal LSI frequency.
+    pub fn period_ms(&self) -> u32 {
+        (4 << self.pr) * (self.rlr + 1) / (LSI_HZ / 1000)
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Mmio for MockIwdg {
+    fn read8(&self, addr: usize) -> u8 {
+        panic!("mock: unmapped read8 at {:#010x}", addr)
+    }
+
+    fn read32(&self, addr: usize) -> u32 {
+        match addr {
+            SR => {
+                let polls = self.update_polls.get();
+                if polls > 0 {
+                    self.update_polls.set(polls - 1);
+                    SR_PVU | SR_RVU
+                } else {
+                    0
+                }
+            }
+            PR => self.pr,
+            RLR => self.rlr,
+            RCC_CSR => self.csr,
+            _ => panic!("mock: unmapped read32 at {:#010x}", addr),
+        }
+    }
+
+    fn write8(&mut self, addr: usize, _val: u8) {
+        panic!("mock: unmapped write8 at {:#010x}", addr)
+    }
+
+    fn write16(&mut self, addr: usize, _val: u16) {
+        panic!("mock: unmapped write16 at {:#010x}", addr)
+    }
+
+    fn write32(&mut self, addr: usize, val: u32) {
+        match addr {
+            KR => match val {
+                KEY_START => self.running = true,
+                KEY_UNLOCK => self.unlocked = true,
+                KEY_FEED => {
+                    // Reloading also write-protects PR and RLR again.
+                    self.unlocked = false;
+                    self.feeds += 1;
+                }
+                _ => {}
+            },
+            PR if self.unlocked => self.pr = val & 0b111,
+            RLR if self.unlocked => self.rlr = val & RELOAD_MAX,
+            PR | RLR => {}
+            RCC_CSR => {
+                if val & CSR_RMVF != 0 {
+                    self.csr &= !0xFF00_0000;
+                }
+            }
+            _ => panic!("mock: unmapped write32 at {:#010x}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::MockFlash;
+
+    #[test]
+    fn timeout_is_rounded_up() {
+        assert_eq!(iwdg_config(0), None);
+        // 1 ms is 32 LSI ticks: divider 4, 8 counts.
+        assert_eq!(iwdg_config(1), Some((0, 7)));
+        assert_eq!(iwdg_config(500), Some((0, 3999)));
+        assert_eq!(iwdg_config(4000), Some((3, 3999)));
+        assert_eq!(iwdg_config(32_768), Some((6, 0xFFF)));
+        assert_eq!(iwdg_config(32_769), None);
+
+        for ms in [1, 7, 100, 4000, 8000, 30_000] {
+            let mut wdg = Iwdg::new(MockIwdg::default());
+            wdg.start(ms).unwrap();
+            assert!(wdg.mmio().running);
+            assert!(wdg.mmio().period_ms() >= ms);
+            assert!(!wdg.mmio().unlocked);
+        }
+    }
+
+    #[test]
+    fn stuck_update_is_reported() {
+        let mock = MockIwdg::default();
+        mock.update_polls.set(u32::MAX);
+        assert_eq!(Iwdg::new(mock).start(1000), Err(WatchdogError::Timeout));
+        assert_eq!(Iwdg::new(MockIwdg::default()).start(40_000), Err(WatchdogError::InvalidTimeout(40_000)));
+    }
+
+    #[test]
+    fn reset_cause_is_read_once() {
+        let mut mock = MockIwdg { csr: CSR_IWDGRSTF | CSR_PINRSTF, ..Default::default() };
+        assert_eq!(read_reset_cause(&mut mock), ResetCause::Watchdog);
+        assert_eq!(read_reset_cause(&mut mock), ResetCause::Unknown);
+
+        mock.csr = CSR_PORRSTF | CSR_PINRSTF;
+        assert_eq!(read_reset_cause(&mut mock), ResetCause::PowerOn);
+        mock.csr = CSR_SFTRSTF | CSR_PINRSTF;
+        assert_eq!(read_reset_cause(&mut mock), ResetCause::Software);
+        mock.csr = CSR_PINRSTF;
+        assert_eq!(read_reset_cause(&mut mock), ResetCause::Pin);
+    }
+
+    #[test]
+    fn flash_work_feeds_the_watchdog() {
+        let mut flash = MockFlash::new(0x1000, 0x400, 0x100);
+        let mut wdg = Iwdg::new(MockIwdg::default());
+        {
+            let mut fed = FedFlash::new(&mut flash, &mut wdg);
+            // Two sectors erased, three pages programmed.
+            fed.write_region(0x300, &[0x5Au8; 0x300]).unwrap();
+            let mut buf = [0u8; 0x300];
+            fed.read(0x300, &mut buf).unwrap();
+        }
+        assert_eq!(wdg.mmio().feeds, 5);
+        assert!(flash.verify(0x300, &[0x5Au8; 0x300]).is_ok());
+    }
+}
@@ -0,0 +1,17 @@
+/* M2 Bootloader RUST - bootloader memory layout (STM32F411xE).
+   Must match docs/memory_map.md. */
+MEMORY
+{
+  /* Sectors 0-2; sector 3 holds the security counter. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 48K
+  /* Everything but the shared regions at the top. */
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K - 64
+  /* Shared with the application and left out of RAM in both images, so
+     neither startup code zeroes them. Boot information for the application
+     (boot-core/src/bootinfo.rs), requests from it (boot-core/src/mailbox.rs). */
+  BOOT_INFO : ORIGIN = 0x2001FFC0, LENGTH = 48
+  BOOT_REQUEST : ORIGIN = 0x2001FFF0, LENGTH = 16
+}
+
+__boot_info = ORIGIN(BOOT_INFO);
+__boot_request = ORIGIN(BOOT_REQUEST);
@@ -0,0 +1,58 @@
+//! M2 Bootloader RUST
+//! ------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
+//!
+//! Board wiring for the STM32F4 target.
+//!
+//! Owns the static `InternalFlash` and `Iwdg` instances for this MCU and the
+//! high-level convenience API built on them. Everything hardware-independent
+//! lives in the `boot-core` library.
+
+#![allow(dead_code)]
+
+use core::ptr::{addr_of, addr_of_mut};
+
+use boot_core::flash::{Flash, Result};
+use boot_core::stm32f4::{InternalFlash, ProgramSize, VolatileMmio, STM32F411_SECTORS};
+use boot_core::watchdog::Iwdg;
+
+// NOTE: adjust these constants to your MCU memory map in docs/memory_map.md
+const FLASH_BASE_ADDR: usize = 0x0800_0000;
+const FLASH_PAGE_BYTES: usize = 256;
+/// x32 parallelism assumes VDD between 2.7 V and 3.6 V.
+const FLASH_PROGRAM_SIZE: ProgramSize = ProgramSize::X32;
+
+pub(crate) static mut BOOT_INTERNAL_FLASH: InternalFlash = InternalFlash::new(
+    VolatileMmio,
+    FLASH_BASE_ADDR,
+    &STM32F411_SECTORS,
+    FLASH_PAGE_BYTES,
+    FLASH_PROGRAM_SIZE,
+);
+
+/// Started by `init::init_hardware`; keeps running into the application.
+pub(crate) static mut BOOT_WATCHDOG: Iwdg = Iwdg::new(VolatileMmio);
+
+/// Read `buf.len()` bytes from absolute flash address `addr`.
+pub fn read_flash(addr: u32, buf: &mut [u8]) -> Result<()> {
+    let rel = addr as usize - FLASH_BASE_ADDR;
+    unsafe { (*addr_of!(BOOT_INTERNAL_FLASH)).read(rel, buf) }
+}
+
+/// Write `data` to absolute flash address `addr`. This will erase overlapping
+/// sectors and program pages, verifying after each page.
+pub fn write_flash(addr: u32, data: &[u8]) -> Result<()> {
+    let rel = addr as usize - FLASH_BASE_ADDR;
+    unsafe { (*addr_of_mut!(BOOT_INTERNAL_FLASH)).write_region(rel, data) }
+}
+
+/// Verify a region using the global internal flash driver.
+pub fn verify_region_crc_internal(addr: usize, len: usize, expected_crc: u32) -> Result<bool> {
+    // SAFETY: BOOT_INTERNAL_FLASH is a global static mut, access must be single‑threaded.
+    unsafe { (*addr_of!(BOOT_INTERNAL_FLASH)).crc32(addr, len).map(|c| c == expected_crc) }
+}
@@ -16,6 +16,10 @@
 //! specific hardware families via feature flags.
 
 use core::fmt;
+use core::ops::Range;
+
+use boot_core::deinit::Peripherals;
+use boot_core::watchdog::ResetCause;
 
 /// Boot error types returned during hardware initialization.
 #[derive(Debug)]
@@ -23,6 +27,8 @@ pub enum InitError {
     ClockConfig,
     FlashConfig,
     PeripheralInit,
+    Watchdog,
+    Deinit,
     Other(&'static str),
 }
 
@@ -32,6 +38,8 @@ impl fmt::Display for InitError {
             InitError::ClockConfig => write!(f, "Clock configuration failed"),
             InitError::FlashConfig => write!(f, "Flash interface configuration failed"),
             InitError::PeripheralInit => write!(f, "Peripheral initialization failed"),
+            InitError::Watchdog => write!(f, "Watchdog start failed"),
+            InitError::Deinit => write!(f, "Hardware deinitialization failed"),
             InitError::Other(msg) => write!(f, "Other init error: {}", msg),
         }
     }
@@ -40,12 +48,18 @@ impl fmt::Display for InitError {
 /// Result type for hardware initialization.
 pub type Result<T> = core::result::Result<T, InitError>;
 
+/// Peripherals `peripherals_setup` brings up (GPIOA and USART1 for the
+/// update link), reset again by `deinit_hardware`.
+pub const BOOT_PERIPHERALS: Peripherals = Peripherals { ahb1: 1 << 0, ahb2: 0, apb1: 0, apb2: 1 << 4 };
+
 /// Represents the bootloader hardware state.
 /// In a real implementation this may hold MCU-specific peripherals or handles.
 pub struct BootHardware {
     pub clock_speed_hz: u32,
     pub flash_ready: bool,
     pub peripherals_ready: bool,
+    /// Why the MCU reset, read before the flags were cleared.
+    pub reset_cause: ResetCause,
 }
 
 impl BootHardware {
@@ -55,6 +69,7 @@ impl BootHardware {
             clock_speed_hz: 0,
             flash_ready: false,
             peripherals_ready: false,
+            reset_cause: ResetCause::Unknown,
         }
     }
 }
@@ -62,6 +77,8 @@ impl BootHardware {
 /// Initialize all hardware required for the bootloader.
 ///
 /// This function should:
+/// - Record the reset cause and start the independent watchdog with a
+///   period of `watchdog_timeout_ms`.
 /// - Configure the system clock.
 /// - Enable and configure the flash memory interface.
 /// - Initialize essential peripherals (UART/USB) for communication.
@@ -76,7 +93,10 @@ impl BootHardware {
 ///
 /// # Safety
 /// Must be called once at system start before other hardware access.
-pub fn init_hardware() -> Result<BootHardware> {
+pub fn init_hardware(watchdog_timeout_ms: u32) -> Result<BootHardware> {
+    // Watchdog first, so a hang anywhere below is caught too.
+    let reset_cause = watchdog_setup(watchdog_timeout_ms)?;
+
     // System clock configuration placeholder.
     #[cfg(feature = "stm32f4")]
     stm32f4_clock_setup()?;
@@ -93,6 +113,7 @@ pub fn init_hardware() -> Result<BootHardware> {
         clock_speed_hz: system_clock_hz(),
         flash_ready: true,
         peripherals_ready: true,
+        reset_cause,
     })
 }
 
@@ -110,6 +131,50 @@ fn nrf52_clock_setup() -> Result<()> {
     Ok(())
 }
 
+/// Read and clear the reset flags, then start the IWDG. It cannot be stopped
+/// again, so the application inherits it.
+#[cfg(not(test))]
+fn watchdog_setup(timeout_ms: u32) -> Result<ResetCause> {
+    use boot_core::stm32f4::VolatileMmio;
+    use boot_core::watchdog::read_reset_cause;
+
+    let cause = read_reset_cause(&mut VolatileMmio);
+    // SAFETY: single-threaded start-up, nothing else holds the watchdog yet.
+    let watchdog = unsafe { &mut *core::ptr::addr_of_mut!(crate::board::BOOT_WATCHDOG) };
+    watchdog.start(timeout_ms).map_err(|_| InitError::Watchdog)?;
+    Ok(cause)
+}
+
+#[cfg(test)]
+fn watchdog_setup(_timeout_ms: u32) -> Result<ResetCause> {
+    // No RCC or IWDG on the host.
+    Ok(ResetCause::Unknown)
+}
+
+/// Undo `init_hardware` before jumping to the application.
+///
+/// Disables and clears all NVIC interrupts, stops SysTick, resets
+/// `BOOT_PERIPHERALS`, puts the clock tree back to its reset defaults and
+/// zeroes the `scrub` RAM ranges. The watchdog keeps running.
+pub fn deinit_hardware(scrub: &[Range<usize>]) -> Result<()> {
+    deinit_setup(scrub)
+}
+
+#[cfg(not(test))]
+fn deinit_setup(scrub: &[Range<usize>]) -> Result<()> {
+    use boot_core::deinit::{deinit, DeinitConfig};
+    use boot_core::stm32f4::VolatileMmio;
+
+    let config = DeinitConfig { peripherals: BOOT_PERIPHERALS, restore_clocks: true, scrub };
+    deinit(&mut VolatileMmio, &config).map_err(|_| InitError::Deinit)
+}
+
+#[cfg(test)]
+fn deinit_setup(_scrub: &[Range<usize>]) -> Result<()> {
+    // No core peripherals or RCC on the host.
+    Ok(())
+}
+
 fn flash_interface_setup() -> Result<()> {
     // TODO: configure flash wait states, caches, or unlock sequences.
     Ok(())
@@ -122,7 +187,7 @@ fn peripherals_setup() -> Result<()> {
 
 /// Query the configured system clock frequency (in Hz).
 /// Replace with MCU-specific readback.
-fn system_clock_hz() -> u32 {
+pub(crate) fn system_clock_hz() -> u32 {
     // Example placeholder: 48 MHz.
     48_000_000
 }
@@ -133,9 +198,10 @@ mod tests {
 
     #[test]
     fn test_init_hardware_success() {
-        let hw = init_hardware().unwrap();
+        let hw = init_hardware(4000).unwrap();
         assert!(hw.flash_ready);
         assert!(hw.peripherals_ready);
         assert_eq!(hw.clock_speed_hz, 48_000_000);
+        assert!(deinit_hardware(&[]).is_ok());
     }
 }
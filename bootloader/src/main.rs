@@ -12,67 +12,294 @@
 //! This module coordinates hardware initialization, firmware
 //! verification, and update handling. It is the top-level
 //! execution point for the bootloader firmware.
+//!
+//! Failure policy: once `init_hardware` has been called, every path that
+//! cannot go on to the application ends in `recovery_mode`, which keeps the
+//! independent watchdog fed and, after `RECOVERY_TIMEOUT_MS`, resets the
+//! MCU through `SYSRESETREQ` to try booting again. A failure therefore never
+//! turns into a watchdog reset loop, and is never reported to the next boot
+//! as a watchdog reset (which would count against an image on trial).
+
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-#![no_std]
-#![no_main]
+mod board;
+mod init;
 
 use core::panic::PanicInfo;
-use crate::init::init_hardware;
-use crate::updater::{FirmwareUpdater, UpdateMetadata, UpdateError};
-use crate::flash::{read_flash, write_flash, FlashError};
-use crate::verify::verify_crc;
+use boot_core::boot;
+use boot_core::bootinfo::{BootInfo, BOOT_INFO_LEN};
+use boot_core::flash::Flash;
+use boot_core::handoff::{self, VectorTable};
+use boot_core::image::ImageVersion;
+use boot_core::mailbox::{self, BootRequest, MAILBOX_LEN};
+use boot_core::rollback::FlashCounter;
+use boot_core::slot::{Slot, SlotLayout};
+use boot_core::stm32f4::FLASH_MEMORY_BASE;
+use boot_core::watchdog::{FedFlash, ResetCause, Watchdog};
+use crate::init::{deinit_hardware, init_hardware};
+
+/// Image slots, as offsets from the start of internal flash. The primary
+/// slot (sector 5) executes; updates are staged in the secondary slot
+/// (sector 6). Installing swaps them through the scratch sector (7), with
+/// progress kept in the swap status sector (4). Must match the layout in
+/// docs/memory_map.md.
+#[cfg(not(any(feature = "swap-move", feature = "overwrite-only")))]
+const BOOT_SLOTS: SlotLayout = SlotLayout::new(
+    Slot::new(0x0002_0000, 0x0002_0000),
+    Slot::new(0x0004_0000, 0x0002_0000),
+    Slot::new(0x0006_0000, 0x0002_0000),
+    Slot::new(0x0001_0000, 0x0001_0000),
+);
+
+/// With `swap-move` the scratch sector becomes the secondary slot (7) and
+/// the primary slot (sectors 5-6) carries the spare sector the swap moves
+/// through. `overwrite-only` uses the same slots; the status sector then
+/// only holds the install marker.
+#[cfg(any(feature = "swap-move", feature = "overwrite-only"))]
+const BOOT_SLOTS: SlotLayout = SlotLayout::new(
+    Slot::new(0x0002_0000, 0x0004_0000),
+    Slot::new(0x0006_0000, 0x0002_0000),
+    Slot::new(0, 0),
+    Slot::new(0x0001_0000, 0x0001_0000),
+);
+
+/// Anti-rollback security counter, in the last bootloader sector (3). Images
+/// with a lower security version are refused by both the updater and the
+/// boot path. Must match the layout in docs/memory_map.md.
+const SECURITY_COUNTER: FlashCounter = FlashCounter::new(Slot::new(0x0000_C000, 0x4000));
+
+/// Digest algorithm application images are checked with. Low-end boards can
+/// switch to `boot_core::digest::Crc32`; images must be built with the same algorithm.
+type BootDigest = boot_core::digest::Sha256;
+
+/// Boots a new image gets to confirm itself before it is reverted (or, with
+/// nothing to revert to, the bootloader enters recovery mode). At most
+/// `boot_core::trailer::MAX_BOOT_ATTEMPTS`.
+const BOOT_ATTEMPTS: usize = 3;
+
+/// Independent watchdog period. Must outlast the slowest single flash
+/// operation, a 128 KiB sector erase (up to 4 s at x8/x16 parallelism), and
+/// the application's feed interval. A watchdog reset during a trial boot
+/// fails the trial.
+const WATCHDOG_TIMEOUT_MS: u32 = 8000;
+
+/// Time spent in recovery mode before resetting to try booting again.
+const RECOVERY_TIMEOUT_MS: u32 = 60_000;
+
+/// Version of this bootloader, reported to the application in its boot
+/// information. Keep in step with Cargo.toml.
+const BOOTLOADER_VERSION: ImageVersion = ImageVersion::new(0, 1, 0, 0);
 
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     // TODO: implement platform-specific panic behavior (LED blink, reset, etc.)
-    loop {}
+    recovery_mode()
 }
 
 /// Bootloader main function.
 ///
 /// # Safety
 /// Should be called once at reset, after MCU startup.
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn main() -> ! {
     // Initialize hardware.
-    let hw = match init_hardware() {
+    let hw = match init_hardware(WATCHDOG_TIMEOUT_MS) {
         Ok(hw) => hw,
-        Err(_e) => loop {}, // Initialization failed: halt or reset
+        // The watchdog may or may not be running; feeding it is harmless.
+        Err(_e) => recovery_mode(),
+    };
+
+    // A request the application left before resetting. Always cleared, only
+    // honoured after a software reset: SRAM is undefined on power-on.
+    let request = take_boot_request().filter(|_| hw.reset_cause == ResetCause::Software);
+    if request == Some(BootRequest::Update) {
+        recovery_mode();
+    }
+
+    // Erase and program loops feed the watchdog as they go; a stall does not.
+    // SAFETY: single-threaded, and nothing else holds the statics from here on.
+    let (flash, watchdog) = unsafe {
+        (
+            &mut *core::ptr::addr_of_mut!(crate::board::BOOT_INTERNAL_FLASH),
+            &mut *core::ptr::addr_of_mut!(crate::board::BOOT_WATCHDOG),
+        )
+    };
+    let flash = &mut FedFlash::new(flash, watchdog) as &mut dyn Flash;
+
+    if BOOT_SLOTS.check(flash).is_err() || SECURITY_COUNTER.check(flash).is_err() {
+        recovery_mode(); // Slot layout does not match this part's sectors.
+    }
+
+    if request == Some(BootRequest::BootSecondary) {
+        // On failure the selection below runs as if nothing was asked.
+        let _ = boot::activate_secondary::<BootDigest>(flash, &BOOT_SLOTS, &SECURITY_COUNTER);
+    }
+
+    // Header, payload digest and (with `secure-boot`) signature must all
+    // check out before we jump into the slot. A newer valid image staged in
+    // the secondary slot is promoted to the primary slot first, and an
+    // unconfirmed image is reverted after BOOT_ATTEMPTS boots. Images below
+    // the security counter are refused.
+    let watchdog_reset = hw.reset_cause == ResetCause::Watchdog;
+    let selected = boot::select_image::<BootDigest>(flash, &BOOT_SLOTS, &SECURITY_COUNTER, BOOT_ATTEMPTS, watchdog_reset);
+    let header = match selected {
+        Ok(header) => header,
+        Err(_) => recovery_mode(), // No valid image to boot.
     };
 
-    // Example: check if new firmware is present and valid.
-    let update_meta = UpdateMetadata {
-        target_addr: 0x0800_0000, // Adjust to actual firmware location
-        image_size: 64 * 1024,    // Example size
-        expected_crc: 0xDEADBEEF, // Example CRC, replace with actual
+    // The image verified; make sure it can actually start from this slot.
+    let table = match handoff::read_vector_table(
+        flash,
+        FLASH_MEMORY_BASE as u32,
+        BOOT_SLOTS.primary,
+        &header,
+        &handoff::STM32F411_RAM,
+    ) {
+        Ok(table) => table,
+        Err(_) => recovery_mode(), // Not linked for the primary slot.
     };
 
-    // Attempt firmware update (stub for demonstration).
-    let mut updater_flash = unsafe { &mut crate::flash::BOOT_INTERNAL_FLASH as &mut dyn crate::flash::Flash };
-    match FirmwareUpdater::begin_update(updater_flash, update_meta) {
-        Ok(mut updater) => {
-            // In real implementation, fetch data chunks from communication interface
-            // Here we just simulate writing empty data.
-            let data = [0xFFu8; 1024];
-            let mut offset = 0;
-            while offset < update_meta.image_size {
-                let chunk_size = core::cmp::min(data.len(), update_meta.image_size - offset);
-                updater.write_chunk(offset, &data[..chunk_size]).ok();
-                offset += chunk_size;
-            }
-            let _ = updater.finalize_update();
-        }
-        Err(_e) => {
-            // No update, continue to existing firmware.
-        }
+    // Tell the application how it was started. The region lies outside RAM
+    // as the linker sees it, so the RAM scrub leaves it alone. Without a
+    // record the application just sees no boot information.
+    let info = BootInfo::gather(
+        flash,
+        &BOOT_SLOTS,
+        FLASH_MEMORY_BASE as u32,
+        &header,
+        hw.reset_cause,
+        BOOTLOADER_VERSION,
+    );
+    if let Ok(info) = info {
+        publish_boot_info(&info);
     }
 
-    // After update or if no update, jump to application.
-    jump_to_application();
+    // Leave the MCU as reset left it, minus the watchdog. Interrupts stay
+    // masked from here until the jump re-enables them for the application.
+    // What verification left in RAM is wiped at the jump, once no bootloader
+    // frame below the final stack pointer is live any more.
+    cortex_m::interrupt::disable();
+    if deinit_hardware(&[]).is_err() {
+        recovery_mode();
+    }
+
+    // SAFETY: the table was checked above and nothing of the bootloader is
+    // used after the jump.
+    unsafe { jump_to_application(&table) }
+}
+
+/// Take the application's request out of the mailbox (`__boot_request`, see
+/// memory.x), leaving it empty.
+#[cfg(not(test))]
+fn take_boot_request() -> Option<BootRequest> {
+    extern "C" {
+        // Start of the BOOT_REQUEST region, from memory.x.
+        static mut __boot_request: [u8; MAILBOX_LEN];
+    }
+    // SAFETY: the region is reserved for the mailbox in both images' memory.x
+    // and nothing else in the bootloader refers to it.
+    let mailbox = unsafe { core::ptr::addr_of_mut!(__boot_request) as *mut u8 };
+    let mut bytes = [0u8; MAILBOX_LEN];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = unsafe { core::ptr::read_volatile(mailbox.add(i)) };
+    }
+    let request = mailbox::take(&mut bytes);
+    for (i, byte) in bytes.into_iter().enumerate() {
+        unsafe { core::ptr::write_volatile(mailbox.add(i), byte) };
+    }
+    request
+}
+
+/// Stay in the bootloader with the watchdog fed for `RECOVERY_TIMEOUT_MS`,
+/// then reset to try booting again: on request of the application, and
+/// wherever booting cannot go on (see the failure policy above). There is no
+/// update link yet, so nothing is received in the meantime.
+#[cfg(not(test))]
+fn recovery_mode() -> ! {
+    // SAFETY: reached with nothing else left running; any borrow of the
+    // watchdog in `main` is no longer used.
+    let watchdog = unsafe { &mut *core::ptr::addr_of_mut!(crate::board::BOOT_WATCHDOG) };
+    // At least a millisecond: the clock may still run slower than configured.
+    let cycles_per_ms = crate::init::system_clock_hz() / 1000;
+    for _ in 0..RECOVERY_TIMEOUT_MS {
+        // TODO: receive a new image into the secondary slot over the update link.
+        cortex_m::asm::delay(cycles_per_ms);
+        watchdog.feed();
+    }
+    // A software reset, so the next boot does not take it for a hang.
+    cortex_m::peripheral::SCB::sys_reset()
 }
 
-/// Placeholder function to jump to the main application.
-fn jump_to_application() -> ! {
-    // TODO: implement vector table relocation and jump to reset handler
-    loop {}
+/// Write `info` to the boot information region the application reads it
+/// from (`__boot_info`, see memory.x).
+#[cfg(not(test))]
+fn publish_boot_info(info: &BootInfo) {
+    extern "C" {
+        // Start of the BOOT_INFO region, from memory.x.
+        static mut __boot_info: [u8; BOOT_INFO_LEN];
+    }
+    // SAFETY: the region is reserved for this record in both images' memory.x
+    // and nothing else in the bootloader refers to it.
+    let dst = unsafe { core::ptr::addr_of_mut!(__boot_info) as *mut u8 };
+    for (i, byte) in info.to_bytes().into_iter().enumerate() {
+        unsafe { core::ptr::write_volatile(dst.add(i), byte) };
+    }
+}
+
+/// Point VTOR at the application's vector table, zero the RAM below the
+/// stack pointer, load the application's initial stack pointer into MSP and
+/// branch to its reset handler.
+///
+/// The zeroed RAM runs from the end of the bootloader's statics up to the
+/// stack pointer the scrub itself runs at. The stack grows down into it, so
+/// it holds every frame that has returned, verification included. The scrub
+/// is done in assembly that uses no stack, so it cannot clear a live frame.
+///
+/// # Safety
+/// `table` must come from `handoff::read_vector_table`, interrupts must be
+/// masked (PRIMASK set) and the NVIC and SysTick off. PRIMASK is cleared just
+/// before the branch. Does not return; the bootloader's stack is abandoned.
+#[cfg(not(test))]
+unsafe fn jump_to_application(table: &VectorTable) -> ! {
+    extern "C" {
+        // End of .bss/.uninit, from the cortex-m-rt linker script. Word
+        // aligned.
+        static __sheap: u8;
+    }
+    let scrub_start = core::ptr::addr_of!(__sheap) as u32;
+
+    // SAFETY: SCB_VTOR is the architectural VTOR register; interrupts raised
+    // from here on are taken through the application's table.
+    core::ptr::write_volatile(handoff::SCB_VTOR as *mut u32, table.addr);
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+
+    // The stack pointer and reset handler are the ones read_vector_table
+    // checked. r0 and r1 are used as scratch: `noreturn` allows no outputs,
+    // so they are passed in and clobbered, as `cortex_m::asm::bootstrap`
+    // does.
+    core::arch::asm!(
+        "mov r1, sp",
+        "2:",
+        "cmp r0, r1",
+        "bhs 3f",
+        "str r2, [r0], #4",
+        "b 2b",
+        "3:",
+        "dsb",
+        "msr msp, r3",
+        // NVIC and SysTick are off, so nothing is taken before the branch;
+        // the application starts with PRIMASK clear, as after a reset.
+        "cpsie i",
+        "bx r12",
+        in("r0") scrub_start,
+        in("r1") 0u32,
+        in("r2") 0u32,
+        in("r3") table.initial_sp,
+        in("r12") table.reset_handler,
+        options(noreturn, nostack),
+    )
 }
@@ -9,4 +9,4 @@
 # GitHub  : <https://github.com/m-a-h-b-u-b/M2-Bootloader-Rust>
 
 probe-rs-cli download target/thumbv7em-none-eabihf/debug/bootloader --chip STM32F411RE
-probe-rs-cli download target/thumbv7em-none-eabihf/debug/app --chip STM32F411RE --base-address 0x08004000
\ No newline at end of file
+probe-rs-cli download target/thumbv7em-none-eabihf/debug/app --chip STM32F411RE --base-address 0x08020200
\ No newline at end of file